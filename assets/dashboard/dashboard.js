@@ -0,0 +1,170 @@
+"use strict";
+
+// Keys in /api/v2/state that are not rooms.
+const SYSTEM_KEYS = ["stove", "pipe", "outdoor"];
+
+// Charts are refreshed at most this often (live values update on every event).
+const CHART_REFRESH_MS = 5 * 60 * 1000;
+
+const rooms = new Map();
+
+function formatTemperature(value) {
+  return value === undefined || value === null ? "–" : `${Number(value).toFixed(1)}°C`;
+}
+
+function setState(element, enabled) {
+  element.textContent = enabled ? "on" : "off";
+  element.classList.toggle("on", enabled);
+  element.classList.toggle("off", !enabled);
+}
+
+function roomElement(key, label) {
+  if (!rooms.has(key)) {
+    const template = document.getElementById("room-template");
+    const section = template.content.firstElementChild.cloneNode(true);
+    section.querySelector(".name").textContent = label || key;
+    document.getElementById("rooms").appendChild(section);
+    rooms.set(key, section);
+  }
+  return rooms.get(key);
+}
+
+function updateTemperature(key, temperature, expected) {
+  if (key === "pipe" || key === "outdoor") {
+    document.getElementById(key).textContent = formatTemperature(temperature);
+    if (key === "outdoor") {
+      document.getElementById("outdoor-tile").hidden = false;
+    }
+    return;
+  }
+
+  const room = roomElement(key);
+  room.querySelector(".temperature").textContent = formatTemperature(temperature);
+  room.querySelector(".expected").textContent =
+    expected === undefined || expected === null ? "" : `/ ${formatTemperature(expected)}`;
+}
+
+function updateRelay(key, enabled) {
+  if (key === "stove") {
+    setState(document.getElementById("stove"), enabled);
+  } else {
+    setState(roomElement(key).querySelector(".valve"), enabled);
+  }
+}
+
+async function loadState() {
+  const response = await fetch("/api/v2/state");
+  if (!response.ok) {
+    return;
+  }
+
+  const state = await response.json();
+  Object.keys(state)
+    .filter((key) => !SYSTEM_KEYS.includes(key))
+    .sort((a, b) => state[a].label.localeCompare(state[b].label))
+    .forEach((key) => roomElement(key, state[key].label));
+
+  for (const [key, values] of Object.entries(state)) {
+    if (values.temperature !== null) {
+      updateTemperature(key, values.temperature, values.expected_temperature);
+    }
+    if (values.heating_enabled !== null) {
+      updateRelay(key, values.heating_enabled);
+    }
+  }
+}
+
+function svgElement(name, attributes) {
+  const element = document.createElementNS("http://www.w3.org/2000/svg", name);
+  for (const [attribute, value] of Object.entries(attributes)) {
+    element.setAttribute(attribute, value);
+  }
+  return element;
+}
+
+function drawChart(svg, history) {
+  const from = Date.parse(history.from);
+  const to = Date.parse(history.to);
+  const temperatures = history.temperatures;
+  const values = temperatures.flatMap((bucket) =>
+    bucket.expected_temperature === null ? [bucket.avg] : [bucket.avg, bucket.expected_temperature]
+  );
+  const min = Math.floor(Math.min(...values) - 0.5);
+  const max = Math.ceil(Math.max(...values) + 0.5);
+  const x = (timestamp) => ((Date.parse(timestamp) - from) / (to - from)) * 300;
+  const y = (value) => 100 - ((value - min) / (max - min)) * 100;
+  const width = (history.resolution_seconds * 1000 * 300) / (to - from);
+
+  svg.replaceChildren();
+  svg.appendChild(svgElement("title", {})).textContent = `${min}–${max}°C`;
+
+  for (const bucket of history.states) {
+    if (bucket.enabled_ratio > 0) {
+      svg.appendChild(
+        svgElement("rect", {
+          class: "heating",
+          x: x(bucket.timestamp),
+          y: 0,
+          width: width,
+          height: 100,
+          opacity: bucket.enabled_ratio,
+        })
+      );
+    }
+  }
+
+  const line = (select) =>
+    temperatures
+      .filter((bucket) => select(bucket) !== null)
+      .map((bucket, index) => `${index ? "L" : "M"}${x(bucket.timestamp)},${y(select(bucket))}`)
+      .join("");
+
+  svg.appendChild(svgElement("path", { class: "expected-line", d: line((bucket) => bucket.expected_temperature) }));
+  svg.appendChild(svgElement("path", { class: "temperature-line", d: line((bucket) => bucket.avg) }));
+}
+
+async function loadCharts() {
+  const hours = Number(document.getElementById("range").value);
+  const from = new Date(Date.now() - hours * 3600 * 1000).toISOString();
+
+  // one request at a time to keep the load on the server low
+  for (const [key, room] of rooms) {
+    const response = await fetch(`/api/v2/history/${encodeURIComponent(key)}?from=${encodeURIComponent(from)}`);
+    if (!response.ok) {
+      continue;
+    }
+    const history = await response.json();
+    if (history.temperatures.length > 0) {
+      drawChart(room.querySelector(".chart"), history);
+    }
+  }
+}
+
+function subscribe() {
+  const events = new EventSource("/api/v2/events");
+
+  events.addEventListener("temperature", (message) => {
+    const event = JSON.parse(message.data);
+    updateTemperature(event.key, event.temperature, event.expected_temperature);
+  });
+  events.addEventListener("relay_state", (message) => {
+    const event = JSON.parse(message.data);
+    updateRelay(event.key, event.heating_enabled);
+  });
+  // the expected temperatures change with overrides; reload them
+  events.addEventListener("override_set", loadState);
+  events.addEventListener("override_cancelled", loadState);
+  // catch up with changes missed while disconnected
+  events.addEventListener("open", loadState);
+}
+
+async function start() {
+  await loadState();
+  await loadCharts();
+  subscribe();
+
+  document.getElementById("range").addEventListener("change", loadCharts);
+  setInterval(loadCharts, CHART_REFRESH_MS);
+}
+
+start();
@@ -19,3 +19,15 @@ function run() {
   echo "${blue}Running ${yellow}$*${reset}"
   eval "$@"
 }
+
+# Exits unless all relays in the given configuration use the sysfs backend. The
+# GPIO character device lines are held by the service and released (back to
+# off) when it stops, so they cannot be switched from a script.
+function require_sysfs_relays() {
+  local cdev_relays
+  cdev_relays=$(jq -r '[(.rooms[] | select(.valve_relay.backend == "cdev") | .key), (select(.stove_relay.backend == "cdev") | "stove")] | join(", ")' "$1")
+  if [ -n "$cdev_relays" ]; then
+    echo "Relays ${cdev_relays} use the cdev backend, which this script does not support (the service holds their GPIO lines)." >&2
+    exit 1
+  fi
+}
@@ -1,37 +1,179 @@
 use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use warp::http::StatusCode;
 use warp::Filter;
 
+use crate::dashboard;
+use crate::error::NeuroheatError;
+use crate::events::{Event, Events};
+use crate::health::{Health, Status};
+use crate::heating_configuration::HeatingConfiguration;
+use crate::history::{History, HistoryParams, HistoryQuery};
+use crate::metrics::Metrics;
 use crate::repo;
+use crate::temperature_override::OverrideRequest;
 
 const LOGGER_TARGET: &str = concat!(env!("CARGO_PKG_NAME"), "::api");
 
-pub async fn start_server(conn: Arc<Mutex<Connection>>, port: u16) {
+pub async fn start_server(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    port: u16,
+) {
+    warp::serve(routes(config, conn, metrics, events))
+        .run(([0, 0, 0, 0], port))
+        .await;
+}
+
+fn routes(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
     let log = warp::log(LOGGER_TARGET);
 
+    // Endpoints that always returned typed JSON are served under both
+    // `/api` and `/api/v2`.
+    let api = warp::path("api").and(warp::path("v2").or(warp::any()).unify());
+
     let temperature_by_room = warp::path!("api" / "temperatures" / String)
         .and(warp::get())
         .and(with_db(conn.clone()))
         .and_then(get_temperature_by_room)
         .with(log);
 
+    let temperature_by_room_v2 = warp::path!("api" / "v2" / "temperatures" / String)
+        .and(warp::get())
+        .and(with_db(conn.clone()))
+        .and_then(get_temperature_by_room_v2)
+        .with(log);
+
     let state = warp::path!("api" / "state")
         .and(warp::get())
         .and(with_db(conn.clone()))
         .and_then(get_state)
         .with(log);
 
-    let routes = temperature_by_room.or(state);
+    let state_v2 = warp::path!("api" / "v2" / "state")
+        .and(warp::get())
+        .and(with_db(conn.clone()))
+        .and_then(get_state_v2)
+        .with(log);
+
+    let history = api
+        .and(warp::path!("history" / String))
+        .and(warp::get())
+        .and(warp::query::<HistoryParams>())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and_then(get_history)
+        .with(log);
+
+    let overrides = api
+        .and(warp::path!("overrides"))
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and_then(get_overrides)
+        .with(log);
+
+    let override_by_room = api
+        .and(warp::path!("rooms" / String / "override"))
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and_then(get_override_by_room)
+        .with(log);
+
+    let create_override = api
+        .and(warp::path!("rooms" / String / "override"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_events(events.clone()))
+        .and_then(create_override)
+        .with(log);
+
+    let delete_override = api
+        .and(warp::path!("rooms" / String / "override"))
+        .and(warp::delete())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_events(events.clone()))
+        .and_then(delete_override)
+        .with(log);
+
+    let events = api
+        .and(warp::path!("events"))
+        .and(warp::get())
+        .and(with_events(events))
+        .and_then(get_events)
+        .with(log);
+
+    let health = api
+        .and(warp::path!("health"))
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and_then(get_health)
+        .with(log);
 
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_metrics(metrics))
+        .and_then(get_metrics)
+        .with(log);
+
+    temperature_by_room
+        .or(temperature_by_room_v2)
+        .or(state)
+        .or(state_v2)
+        .or(history)
+        .or(overrides)
+        .or(override_by_room)
+        .or(create_override)
+        .or(delete_override)
+        .or(events)
+        .or(health)
+        .or(metrics)
+        .or(dashboard::routes().with(log))
+        .recover(handle_rejection)
 }
 
 async fn get_state(conn: Arc<Mutex<Connection>>) -> Result<impl warp::Reply, warp::Rejection> {
+    match repo::get_current_state(&conn) {
+        Ok(result) => {
+            let legacy: BTreeMap<_, _> = result
+                .iter()
+                .map(|(key, state)| (key, state.to_legacy()))
+                .collect();
+            Ok(warp::reply::json(&legacy))
+        }
+        Err(e) => {
+            log::error!("Failed to get current state: {}", e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn get_state_v2(conn: Arc<Mutex<Connection>>) -> Result<impl warp::Reply, warp::Rejection> {
     match repo::get_current_state(&conn) {
         Ok(result) => Ok(warp::reply::json(&result)),
         Err(e) => {
             log::error!("Failed to get current state: {}", e);
-            Err(warp::reject::not_found())
+            Err(warp::reject::custom(e))
         }
     }
 }
@@ -41,16 +183,641 @@ async fn get_temperature_by_room(
     conn: Arc<Mutex<Connection>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match repo::get_latest_temperature(&conn, &key) {
-        Ok(result) => Ok(warp::reply::json(&result)),
+        Ok(Some(result)) => Ok(warp::reply::json(&result.to_legacy())),
+        Ok(None) => Err(not_found(&format!("No temperature for key {}", key))),
+        Err(e) => {
+            log::error!("Failed to get temperature for key {}: {}", key, e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn get_temperature_by_room_v2(
+    key: String,
+    conn: Arc<Mutex<Connection>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match repo::get_latest_temperature(&conn, &key) {
+        Ok(Some(result)) => Ok(warp::reply::json(&result)),
+        Ok(None) => Err(not_found(&format!("No temperature for key {}", key))),
         Err(e) => {
             log::error!("Failed to get temperature for key {}: {}", key, e);
-            Err(warp::reject::not_found())
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn get_history(
+    key: String,
+    params: HistoryParams,
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let query = HistoryQuery::from_params(&params, config.clock.now()).map_err(|e| {
+        log::warn!("Invalid history query for key {}: {}", key, e);
+        warp::reject::custom(e)
+    })?;
+
+    let label = match repo::get_label(&conn, &key) {
+        Ok(Some(label)) => label,
+        Ok(None) => return Err(not_found(&format!("Unknown key {}", key))),
+        Err(e) => {
+            log::error!("Failed to get label for key {}: {}", key, e);
+            return Err(warp::reject::custom(e));
+        }
+    };
+
+    let history = repo::get_temperature_history(&conn, &key, &query).and_then(|temperatures| {
+        let states = repo::get_state_history(&conn, &key, &query)?;
+        Ok(History {
+            key: key.clone(),
+            label,
+            from: query.from,
+            to: query.to,
+            resolution_seconds: query.resolution.num_seconds(),
+            temperatures,
+            states,
+        })
+    });
+
+    match history {
+        Ok(history) => Ok(warp::reply::json(&history)),
+        Err(e) => {
+            log::error!("Failed to get history for key {}: {}", key, e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn get_overrides(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match repo::get_active_overrides(&conn, config.clock.now()) {
+        Ok(result) => Ok(warp::reply::json(&result)),
+        Err(e) => {
+            log::error!("Failed to get overrides: {}", e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn get_override_by_room(
+    key: String,
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match repo::get_active_override(&conn, &key, config.clock.now()) {
+        Ok(Some(result)) => Ok(warp::reply::json(&result)),
+        Ok(None) => Err(not_found(&format!("No active override for key {}", key))),
+        Err(e) => {
+            log::error!("Failed to get override for key {}: {}", key, e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+async fn create_override(
+    key: String,
+    request: OverrideRequest,
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    events: Arc<Events>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let room = match config.rooms.iter().find(|room| room.key == key) {
+        Some(room) => room,
+        None => return Err(not_found(&format!("Unknown room {}", key))),
+    };
+
+    let temperature_override = request
+        .into_override(room, config.clock.now())
+        .map_err(|e| {
+            log::warn!("Invalid override for key {}: {}", key, e);
+            warp::reject::custom(e)
+        })?;
+
+    match repo::store_override(&conn, &temperature_override) {
+        Ok(()) => {
+            log::info!(
+                "Room: {}, Overriding expected temperature with {:.1}°C until {}",
+                room.name,
+                temperature_override.temperature,
+                temperature_override.expires_at
+            );
+            let body = warp::reply::json(&temperature_override);
+            events.publish(Event::OverrideSet {
+                temperature_override,
+            });
+            Ok(warp::reply::with_status(body, StatusCode::CREATED))
+        }
+        Err(e) => {
+            log::error!("Failed to store override for key {}: {}", key, e);
+            Err(warp::reject::custom(e))
         }
     }
 }
 
+async fn delete_override(
+    key: String,
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    events: Arc<Events>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = config.clock.now();
+    match repo::delete_override(&conn, &key, now) {
+        Ok(true) => {
+            log::info!("Key: {}, Override cancelled", key);
+            events.publish(Event::OverrideCancelled {
+                key,
+                timestamp: now,
+            });
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(not_found(&format!("No active override for key {}", key))),
+        Err(e) => {
+            log::error!("Failed to delete override for key {}: {}", key, e);
+            Err(warp::reject::custom(e))
+        }
+    }
+}
+
+/// Reports the health of the application. Responds with 503 when a
+/// critical check fails so that uptime monitors can alert on the status code.
+async fn get_health(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let health = Health::check(&config, &conn, &metrics, config.clock.now());
+    let status = if health.status == Status::Failing {
+        log::warn!("Health check failed: {:?}", health);
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&health), status))
+}
+
+/// Streams events as Server-Sent Events. Each message is named after the
+/// event type and carries the event as JSON.
+async fn get_events(events: Arc<Events>) -> Result<impl warp::Reply, warp::Rejection> {
+    let stream = event_stream(events.subscribe());
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+/// Turns the events into Server-Sent Events. A subscriber that falls
+/// behind skips the events it missed and continues with the next one.
+fn event_stream(
+    receiver: broadcast::Receiver<Event>,
+) -> impl Stream<Item = Result<warp::sse::Event, serde_json::Error>> {
+    BroadcastStream::new(receiver).filter_map(|event| match event {
+        Ok(event) => Some(
+            warp::sse::Event::default()
+                .event(event.name())
+                .json_data(&event),
+        ),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            log::warn!("Event stream lagged behind, skipped {} events", skipped);
+            None
+        }
+    })
+}
+
+async fn get_metrics(metrics: Arc<Metrics>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::with_header(
+        metrics.render(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+impl warp::reject::Reject for NeuroheatError {}
+
+fn not_found(msg: &str) -> warp::Rejection {
+    warp::reject::custom(NeuroheatError::NotFoundError(msg.to_string()))
+}
+
+/// Turns rejections into JSON error responses with a machine-readable
+/// `code` next to the human-readable `error` message.
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let (status, code, message) = if let Some(e) = rejection.find::<NeuroheatError>() {
+        let (status, code) = match e {
+            NeuroheatError::NotFoundError(_) => (StatusCode::NOT_FOUND, "not_found"),
+            NeuroheatError::ValidationError(_) => (StatusCode::BAD_REQUEST, "invalid_request"),
+            NeuroheatError::DatabaseUnavailable(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "database_unavailable")
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        };
+        (status, code, e.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_request", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_request", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            e.to_string(),
+        )
+    } else {
+        log::error!("Unhandled rejection: {:?}", rejection);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal server error".to_string(),
+        )
+    };
+
+    let body = warp::reply::json(&serde_json::json!({ "code": code, "error": message }));
+    Ok(warp::reply::with_status(body, status))
+}
+
+fn with_metrics(
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (Arc<Metrics>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
+
+fn with_events(
+    events: Arc<Events>,
+) -> impl Filter<Extract = (Arc<Events>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || events.clone())
+}
+
+fn with_config(
+    config: Arc<HeatingConfiguration>,
+) -> impl Filter<Extract = (Arc<HeatingConfiguration>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
 fn with_db(
     conn: Arc<Mutex<Connection>>,
 ) -> impl Filter<Extract = (Arc<Mutex<Connection>>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || conn.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config_json_with_schedule, start, with_settings, Harness, CONFIG};
+    use chrono::TimeZone;
+    use warp::Reply;
+
+    async fn status_and_code(rejection: warp::Rejection) -> (StatusCode, String) {
+        let response = handle_rejection(rejection).await.unwrap().into_response();
+        let status = response.status();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["code"].as_str().unwrap().to_string())
+    }
+
+    fn sqlite_error(code: std::os::raw::c_int) -> NeuroheatError {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None).into()
+    }
+
+    #[tokio::test]
+    async fn maps_errors_to_status_codes() {
+        let cases = [
+            (
+                NeuroheatError::NotFoundError("office".to_string()),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                NeuroheatError::ValidationError("temperature".to_string()),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                sqlite_error(rusqlite::ffi::SQLITE_BUSY),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database_unavailable",
+            ),
+            (
+                sqlite_error(rusqlite::ffi::SQLITE_LOCKED),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database_unavailable",
+            ),
+            (
+                sqlite_error(rusqlite::ffi::SQLITE_CORRUPT),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+            (
+                NeuroheatError::DatabaseError("no such table".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+            (
+                NeuroheatError::RelayError("pin 1".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+        ];
+
+        for (error, status, code) in cases {
+            let message = error.to_string();
+            assert_eq!(
+                status_and_code(warp::reject::custom(error)).await,
+                (status, code.to_string()),
+                "{}",
+                message
+            );
+        }
+        assert_eq!(
+            status_and_code(warp::reject::not_found()).await,
+            (StatusCode::NOT_FOUND, "not_found".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn poisoned_connection_is_unavailable() {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        let poisoned = conn.clone();
+        std::thread::spawn(move || {
+            let _conn = poisoned.lock().unwrap();
+            panic!("poison the connection");
+        })
+        .join()
+        .unwrap_err();
+
+        let error = repo::get_current_state(&conn).unwrap_err();
+        assert!(matches!(error, NeuroheatError::DatabaseUnavailable(_)));
+        assert_eq!(
+            status_and_code(warp::reject::custom(error)).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database_unavailable".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn query_errors_are_internal() {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+
+        // The schema is missing.
+        let error = repo::get_current_state(&conn).unwrap_err();
+        assert_eq!(
+            status_and_code(warp::reject::custom(error)).await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error".to_string()
+            )
+        );
+    }
+    #[tokio::test]
+    async fn health_fails_with_503() {
+        let harness = Harness::with_config(&with_settings(
+            CONFIG,
+            r#""outdoor_sensor_id": "outdoor-sensor""#,
+        ));
+        let status = || async {
+            get_health(
+                harness.config.clone(),
+                harness.conn.clone(),
+                harness.metrics.clone(),
+            )
+            .await
+            .unwrap()
+            .into_response()
+            .status()
+        };
+        for sensor in ["office-sensor", "kitchen-sensor", "pipe-sensor"] {
+            harness.sensor(sensor).set(Some(21.0));
+        }
+        // Only the outdoor sensor is missing, so the health is degraded.
+        harness.read_temperatures(1).await;
+        assert_eq!(status().await, StatusCode::OK);
+
+        harness.sensor("outdoor-sensor").set(Some(5.0));
+        harness.read_temperatures(1).await;
+        assert_eq!(status().await, StatusCode::OK);
+
+        harness
+            .metrics
+            .set_stove_fault_active("pipe_not_warming", true);
+        assert_eq!(status().await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+    #[tokio::test]
+    async fn overrides_expire_at_the_next_schedule_change() {
+        let harness = Harness::with_config(&config_json_with_schedule(
+            r#"[
+              { "start": "00:00", "end": "06:00", "temperature": 18.0 },
+              { "start": "06:00", "end": "22:00", "temperature": 21.0 },
+              { "start": "22:00", "end": "24:00", "temperature": 18.0 }
+            ]"#,
+        ));
+        let local = |hour| {
+            chrono::Local
+                .with_ymd_and_hms(2024, 1, 15, hour, 0, 0)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let get = || {
+            get_override_by_room(
+                "office".to_string(),
+                harness.config.clone(),
+                harness.conn.clone(),
+            )
+        };
+        harness.clock.set(local(12));
+
+        let response = create_override(
+            "office".to_string(),
+            OverrideRequest {
+                temperature: 23.0,
+                duration_minutes: None,
+            },
+            harness.config.clone(),
+            harness.conn.clone(),
+            harness.events.clone(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["expires_at"], serde_json::json!(local(22)));
+
+        harness.clock.set(local(22) - chrono::Duration::seconds(1));
+        assert!(get().await.is_ok());
+        harness.clock.set(local(22));
+        let rejection = get().await.err().unwrap();
+        assert!(matches!(
+            rejection.find::<NeuroheatError>(),
+            Some(NeuroheatError::NotFoundError(_))
+        ));
+    }
+    /// Returns the status and the JSON body of a GET request.
+    async fn get_json(harness: &Harness, path: &str) -> (StatusCode, serde_json::Value) {
+        let response = warp::test::request()
+            .path(path)
+            .reply(&routes(
+                harness.config.clone(),
+                harness.conn.clone(),
+                harness.metrics.clone(),
+                harness.events.clone(),
+            ))
+            .await;
+        (
+            response.status(),
+            serde_json::from_slice(response.body()).unwrap(),
+        )
+    }
+
+    fn harness_with_state() -> Harness {
+        let harness = Harness::new();
+        let now = harness.config.clock.now();
+        repo::store_temperature(&harness.conn, "office", 20.5, Some(21.0), now).unwrap();
+        repo::store_temperature(&harness.conn, "kitchen", 22.0, None, now).unwrap();
+        repo::store_state(&harness.conn, "office", true, now).unwrap();
+        repo::store_state(&harness.conn, "stove", false, now).unwrap();
+        harness
+    }
+
+    #[tokio::test]
+    async fn serves_the_legacy_state_with_string_values() {
+        let harness = harness_with_state();
+
+        // The field order of the legacy objects was never fixed, so only
+        // the parsed values are compared.
+        assert_eq!(
+            get_json(&harness, "/api/state").await,
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "kitchen": {
+                        "label": "Kitchen",
+                        "timestamp": "2024-01-15 12:00:00 UTC",
+                        "temperature": "22"
+                    },
+                    "office": {
+                        "label": "Office",
+                        "timestamp": "2024-01-15 12:00:00 UTC",
+                        "temperature": "20.5",
+                        "expected_temperature": "21",
+                        "heating_enabled": "true"
+                    },
+                    "stove": {
+                        "label": "Stove",
+                        "timestamp": "2024-01-15 12:00:00 UTC",
+                        "heating_enabled": "false"
+                    }
+                })
+            )
+        );
+        assert_eq!(
+            get_json(&harness, "/api/temperatures/office").await,
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "key": "office",
+                    "label": "Office",
+                    "timestamp": "2024-01-15 12:00:00 UTC",
+                    "temperature": "20.5",
+                    "expected_temperature": "21"
+                })
+            )
+        );
+        let (_, kitchen) = get_json(&harness, "/api/temperatures/kitchen").await;
+        assert!(kitchen.get("expected_temperature").is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_typed_values_under_v2() {
+        let harness = harness_with_state();
+
+        let (status, state) = get_json(&harness, "/api/v2/state").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            state["office"],
+            serde_json::json!({
+                "label": "Office",
+                "timestamp": "2024-01-15T12:00:00Z",
+                "temperature": 20.5,
+                "expected_temperature": 21.0,
+                "heating_enabled": true
+            })
+        );
+        assert_eq!(state["stove"]["temperature"], serde_json::Value::Null);
+        assert_eq!(state["stove"]["heating_enabled"], false);
+        assert_eq!(
+            get_json(&harness, "/api/v2/temperatures/kitchen").await,
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "key": "kitchen",
+                    "label": "Kitchen",
+                    "timestamp": "2024-01-15T12:00:00Z",
+                    "temperature": 22.0,
+                    "expected_temperature": null
+                })
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_the_newer_endpoints_under_both_prefixes() {
+        let harness = harness_with_state();
+
+        for path in ["/overrides", "/history/office"] {
+            let legacy = get_json(&harness, &format!("/api{}", path)).await;
+            assert_eq!(legacy.0, StatusCode::OK, "{}", path);
+            assert_eq!(
+                get_json(&harness, &format!("/api/v2{}", path)).await,
+                legacy
+            );
+        }
+        for prefix in ["/api", "/api/v2"] {
+            let (status, body) =
+                get_json(&harness, &format!("{}/temperatures/attic", prefix)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["code"], "not_found");
+        }
+    }
+    #[tokio::test]
+    async fn event_stream_continues_after_lagging() {
+        let events = Events::new();
+        let stream = event_stream(events.subscribe());
+        tokio::pin!(stream);
+        let office = |temperature| {
+            events.temperature("office", temperature, None, start());
+        };
+
+        // More events than the channel keeps.
+        for temperature in 0..70 {
+            office(temperature as f32);
+        }
+        let first = stream.next().await.unwrap().unwrap().to_string();
+        assert!(first.starts_with("event:temperature\n"), "{}", first);
+        assert!(first.contains(r#""temperature":6.0"#), "{}", first);
+        for _ in 7..70 {
+            stream.next().await.unwrap().unwrap();
+        }
+
+        office(100.0);
+        let next = stream.next().await.unwrap().unwrap().to_string();
+        assert!(next.contains(r#""temperature":100.0"#), "{}", next);
+    }
+}
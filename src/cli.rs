@@ -1,4 +1,5 @@
-use clap::Parser;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(name = "neuroheat")]
@@ -6,19 +7,69 @@ use clap::Parser;
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Neuroheat system")]
 pub struct Args {
-    #[arg(long, default_value = "info")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(long, default_value = "info", global = true)]
     pub log_level: String,
 
-    #[arg(long, default_value = "neuroheat.db")]
+    #[arg(long, default_value = "neuroheat.db", global = true)]
     pub database_path: String,
 
     #[arg(long, default_value_t = 3030)]
     pub api_port: u16,
 
-    #[arg(long, default_value = "heating_config.json")]
+    #[arg(long, default_value = "heating_config.json", global = true)]
     pub heating_config_path: String,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Run the heating configuration against a simulated house
+    Simulate {
+        /// The thermal model of the house
+        #[arg(long, default_value = "house.json")]
+        house_path: String,
+        /// The simulated time span in hours
+        #[arg(long, default_value_t = 24)]
+        hours: u32,
+        /// The simulated start time (RFC 3339, defaults to the current hour)
+        #[arg(long)]
+        start: Option<DateTime<Utc>>,
+        /// Keep the simulated readings in this new (or empty) database (in
+        /// memory by default)
+        #[arg(long)]
+        output_database_path: Option<String>,
+    },
+    /// Replay the recorded temperatures with a candidate heating configuration
+    Replay {
+        /// The start of the replayed period (RFC 3339)
+        #[arg(long)]
+        from: DateTime<Utc>,
+        /// The end of the replayed period (RFC 3339)
+        #[arg(long)]
+        to: DateTime<Utc>,
+        /// The candidate heating configuration
+        #[arg(long)]
+        config: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Apply pending schema migrations
+    Migrate {
+        /// List pending migrations without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
 pub fn parse_log_level(args: &Args) -> log::LevelFilter {
     match args.log_level.to_lowercase().as_str() {
         "debug" => log::LevelFilter::Debug,
@@ -1,38 +1,88 @@
 use crate::error::NeuroheatError;
+use crate::events::{Event, Events};
 use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
 use crate::repo;
-use chrono::{Duration, Utc};
+use crate::valve_strategy::ValveInput;
+use chrono::{DateTime, Duration, Utc};
 use rusqlite::Connection;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-/// The minimum area in square meters that a stove requires
-/// when turning on heating. If valves are open for a smaller
-/// area, the stove would turn on and off frequently which is
-/// not desired (e.g., higher gas consumption).
-const STOVE_ACTIVATION_AREA: f32 = 16.0;
+/// A decision of the stove controller recorded in the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoveDecision {
+    /// Turned on because enough floor heating area is open.
+    On,
+    /// Turned off because not enough floor heating area is open.
+    Off,
+    /// Turned off because the supply temperature exceeded the ceiling.
+    Overheat,
+    /// The stove is on, but the heating pipe does not warm up.
+    PipeNotWarming,
+}
 
-/// The duration in minutes to wait after a valve state change
-/// before considering it for stove activation. This ensures
-/// the valve has had enough time to open for heating.
-const STOVE_ACTIVATION_DELAY_MINUTES: i64 = 2;
+impl StoveDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StoveDecision::On => "on",
+            StoveDecision::Off => "off",
+            StoveDecision::Overheat => "overheat",
+            StoveDecision::PipeNotWarming => "pipe_not_warming",
+        }
+    }
+}
 
-/// The duration in minutes to look back for temperature readings
-/// when calculating average value for valve control.
-const TEMPERATURE_LOOKBACK_MINUTES: i64 = 10;
+/// The key under which outdoor temperatures are stored.
+pub const OUTDOOR_KEY: &str = "outdoor";
 
-/// The minimum number of temperature readings required for valve
-/// control.
-const MIN_TEMPERATURE_READINGS: usize = 3;
+/// Returns the average outdoor temperature within the lookback window. Returns
+/// `None` without an outdoor sensor or when there are no recent readings.
+pub fn get_outdoor_temperature(
+    config: &HeatingConfiguration,
+    conn: &Arc<Mutex<Connection>>,
+    now: DateTime<Utc>,
+) -> Option<f32> {
+    config.outdoor_sensor_id.as_ref()?;
+
+    let lookback_start =
+        now - Duration::minutes(config.controller.temperature_lookback_minutes as i64);
+    match repo::get_temperatures_since(conn, OUTDOOR_KEY, lookback_start) {
+        Ok(temperatures) if !temperatures.is_empty() => {
+            Some(temperatures.iter().sum::<f32>() / temperatures.len() as f32)
+        }
+        Ok(_) => {
+            log::warn!("No recent outdoor temperature; weather compensation is not applied");
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to get outdoor temperature: {}", e);
+            None
+        }
+    }
+}
 
 pub async fn update_valves(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
 ) -> Result<(), NeuroheatError> {
-    let now = Utc::now();
-    let ten_minutes_ago = now - Duration::minutes(TEMPERATURE_LOOKBACK_MINUTES);
+    let now = config.clock.now();
+    let lookback_start =
+        now - Duration::minutes(config.controller.temperature_lookback_minutes as i64);
+    let valve_states = match repo::get_valve_states_and_timestamps(&conn) {
+        Ok(valve_states) => valve_states,
+        Err(e) => {
+            log::error!("Failed to get valve states: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let outdoor_temperature = get_outdoor_temperature(&config, &conn, now);
 
     for room in &config.rooms {
-        let temperatures = match repo::get_temperatures_since(&conn, &room.key, ten_minutes_ago) {
+        let temperatures = match repo::get_temperatures_since(&conn, &room.key, lookback_start) {
             Ok(temps) => temps,
             Err(e) => {
                 log::error!("Failed to get temperatures for room {}: {}", room.name, e);
@@ -40,14 +90,26 @@ pub async fn update_valves(
             }
         };
 
-        if temperatures.len() < MIN_TEMPERATURE_READINGS {
+        if temperatures.len() < config.controller.min_temperature_readings {
             log::error!("Not enough temperature readings for room {}", room.name);
             continue;
         }
 
         let average_temperature: f32 = temperatures.iter().sum::<f32>() / temperatures.len() as f32;
 
-        let expected_temperature = match room.get_expected_temperature() {
+        let temperature_override = match repo::get_active_override(&conn, &room.key, now) {
+            Ok(temperature_override) => temperature_override,
+            Err(e) => {
+                log::error!("Failed to get override for room {}: {}", room.name, e);
+                None
+            }
+        };
+
+        let expected_temperature = match room.get_expected_temperature(
+            temperature_override.as_ref(),
+            outdoor_temperature,
+            now,
+        ) {
             Some(temp) => temp,
             None => {
                 log::error!("No expected temperature found for room {}", room.name);
@@ -55,8 +117,18 @@ pub async fn update_valves(
             }
         };
 
+        if temperature_override.is_none() {
+            if let Some(adjustment) = room.weather_adjustment(outdoor_temperature) {
+                log::debug!(
+                    "Room: {}, Expected Temperature adjusted by {:+.1}°C for outdoor temperature {:.1}°C",
+                    room.name,
+                    adjustment,
+                    outdoor_temperature.unwrap_or_default()
+                );
+            }
+        }
+
         if let Some(valve_controller) = &room.valve_reader {
-            let desired_state = average_temperature < expected_temperature;
             let current_state = match valve_controller.read_state() {
                 Ok(state) => state,
                 Err(e) => {
@@ -65,30 +137,65 @@ pub async fn update_valves(
                 }
             };
 
+            let input = ValveInput {
+                config: &config,
+                room,
+                conn: &conn,
+                average_temperature,
+                expected_temperature,
+                valve_on: current_state,
+                now,
+            };
+            let decision = match room.valve_strategy.strategy().decide(&input) {
+                Ok(decision) => decision,
+                Err(e) => {
+                    log::error!("Failed to decide on the valve of room {}: {}", room.name, e);
+                    continue;
+                }
+            };
+            let desired_state = decision.open;
+
             if current_state != desired_state {
+                if let Some((recorded_state, last_change)) = valve_states.get(&room.key) {
+                    let min_duration = config.controller.min_valve_duration(room, current_state);
+                    let elapsed = now.signed_duration_since(*last_change);
+
+                    if *recorded_state == current_state && elapsed < min_duration {
+                        log::info!(
+                            "Room: {}, Valve has been {} for {} min (minimum is {} min). Not turning it {} yet.",
+                            room.name,
+                            if current_state { "ON" } else { "OFF" },
+                            elapsed.num_minutes(),
+                            min_duration.num_minutes(),
+                            if desired_state { "ON" } else { "OFF" }
+                        );
+                        continue;
+                    }
+                }
+
                 log::info!(
-                    "Room: {}, Average Temperature: {:.1}°C is {} Expected Temperature: {:.1}°C. Turning valve {}.",
+                    "Room: {}, {}. Turning valve {}.",
                     room.name,
-                    average_temperature,
-                    if desired_state { "less than" } else { "greater than or equal to" },
-                    expected_temperature,
+                    decision.reason,
                     if desired_state { "ON" } else { "OFF" }
                 );
-                if let Err(e) = valve_controller.set_state(desired_state) {
+                let write_result = valve_controller.set_state(desired_state);
+                metrics.record_relay_write(&room.key, write_result.is_ok());
+                if let Err(e) = write_result {
                     log::error!("Failed to set valve state for room {}: {}", room.name, e);
                     continue;
                 }
-                if let Err(e) = repo::store_state(&conn, &room.key, desired_state) {
+                metrics.set_relay_state(&room.key, desired_state);
+                if let Err(e) = repo::store_state(&conn, &room.key, desired_state, now) {
                     log::error!("Failed to store state for room {}: {}", room.name, e);
                     continue;
                 }
+                events.relay_state(&room.key, desired_state, now);
             } else {
                 log::debug!(
-                    "Room: {}, Average Temperature: {:.1}°C is {} Expected Temperature: {:.1}°C. Valve is already {}.",
+                    "Room: {}, {}. Valve is already {}.",
                     room.name,
-                    average_temperature,
-                    if desired_state { "less than" } else { "greater than or equal to" },
-                    expected_temperature,
+                    decision.reason,
                     if desired_state { "ON" } else { "OFF" }
                 );
             }
@@ -104,8 +211,10 @@ pub async fn update_valves(
 pub async fn update_stove_state(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
 ) -> Result<(), NeuroheatError> {
-    let now = Utc::now();
+    let now = config.clock.now();
     let valve_states = repo::get_valve_states_and_timestamps(&conn)?;
 
     let mut total_open_area = 0.0;
@@ -121,7 +230,7 @@ pub async fn update_stove_state(
 
             if *valve_state
                 && now.signed_duration_since(*last_change)
-                    >= Duration::minutes(STOVE_ACTIVATION_DELAY_MINUTES)
+                    >= Duration::minutes(config.controller.stove_activation_delay_minutes as i64)
             {
                 total_open_area += room.area;
                 log::debug!(
@@ -134,30 +243,118 @@ pub async fn update_stove_state(
         }
     }
 
-    // Control the stove based on the total open area
+    let pipe_temperature = match repo::get_temperatures_since(
+        &conn,
+        "pipe",
+        now - Duration::minutes(config.controller.temperature_lookback_minutes as i64),
+    ) {
+        Ok(temperatures) => temperatures.first().copied(),
+        Err(e) => {
+            log::error!("Failed to get pipe temperature: {}", e);
+            None
+        }
+    };
+
+    // Control the stove based on the total open area and the supply temperature
     if let Some(stove_controller) = &config.stove_reader {
         let stove_state = stove_controller.read_state()?;
-        let desired_stove_state = total_open_area >= STOVE_ACTIVATION_AREA;
+        let area_demand = total_open_area >= config.controller.stove_activation_area;
+
+        // Once cut, the stove stays off until the pipe cools down below
+        // the ceiling minus the recovery margin.
+        let supply_limit = if stove_state {
+            config.controller.max_supply_temperature
+        } else {
+            config.controller.max_supply_temperature - config.controller.supply_temperature_recovery
+        };
+        let overheated = pipe_temperature.is_some_and(|temperature| temperature >= supply_limit);
+
+        // A stove that does not warm the pipe is turned off and only tried
+        // again after the warm-up time.
+        let pipe_fault = match valve_states.get("stove") {
+            Some((true, stove_on_since)) if stove_state => {
+                check_pipe_warming(&config, &conn, &metrics, now, *stove_on_since)?
+            }
+            _ => None,
+        };
+        let warmup = Duration::minutes(config.controller.pipe_warmup_minutes as i64);
+        let cooling_off = !stove_state
+            && repo::has_stove_decision_since(&conn, StoveDecision::PipeNotWarming, now - warmup)?;
+        let desired_stove_state =
+            area_demand && !overheated && pipe_fault.is_none() && !cooling_off;
+
+        if pipe_temperature.is_none() {
+            log::warn!("No recent pipe temperature. Supply temperature is not limited.");
+        }
 
         if stove_state != desired_stove_state {
-            if desired_stove_state {
-                log::info!(
-                    "Total open area is {:.1} m². Turning stove ON.",
-                    total_open_area
-                );
+            let (decision, reason) = if area_demand && overheated {
+                (
+                    StoveDecision::Overheat,
+                    format!(
+                        "Pipe temperature is {:.1}°C (limit {:.1}°C).",
+                        pipe_temperature.unwrap_or_default(),
+                        supply_limit
+                    ),
+                )
+            } else if let (true, Some(reason)) = (area_demand, &pipe_fault) {
+                (StoveDecision::PipeNotWarming, reason.clone())
+            } else {
+                (
+                    if desired_stove_state {
+                        StoveDecision::On
+                    } else {
+                        StoveDecision::Off
+                    },
+                    format!("Total open area is {:.1} m².", total_open_area),
+                )
+            };
+
+            if decision == StoveDecision::Overheat {
+                log::warn!("{} Turning stove OFF.", reason);
+                metrics.record_stove_fault(decision.as_str());
+            } else if decision == StoveDecision::PipeNotWarming {
+                log::error!("{} Turning stove OFF.", reason);
+                metrics.record_stove_fault(decision.as_str());
+                metrics.set_stove_fault_active(decision.as_str(), true);
             } else {
                 log::info!(
-                    "Total open area is {:.1} m². Turning stove OFF.",
-                    total_open_area
+                    "{} Turning stove {}.",
+                    reason,
+                    if desired_stove_state { "ON" } else { "OFF" }
                 );
             }
 
-            stove_controller.set_state(desired_stove_state)?;
-            repo::store_state(&conn, "stove", desired_stove_state)?;
+            let write_result = stove_controller.set_state(desired_stove_state);
+            metrics.record_relay_write("stove", write_result.is_ok());
+            write_result?;
+            metrics.set_relay_state("stove", desired_stove_state);
+            repo::store_state(&conn, "stove", desired_stove_state, now)?;
+            events.relay_state("stove", desired_stove_state, now);
+            repo::store_stove_decision(
+                &conn,
+                decision,
+                &reason,
+                pipe_temperature,
+                total_open_area,
+                now,
+            )?;
+            events.publish(Event::StoveDecision {
+                decision: decision.as_str(),
+                reason,
+                timestamp: now,
+            });
         } else {
             log::debug!(
-                "Total open area is {:.1} m². Stove is already {}.",
+                "Total open area is {:.1} m²{}. Stove is already {}.",
                 total_open_area,
+                if overheated && area_demand {
+                    " but the pipe is too hot"
+                } else if cooling_off && area_demand {
+                    " but the pipe did not warm up"
+                } else {
+                    ""
+                },
                 if desired_stove_state { "ON" } else { "OFF" }
             );
         }
@@ -169,3 +366,237 @@ pub async fn update_stove_state(
 
     Ok(())
 }
+
+/// Detects a stove that has been on for a while without the heating pipe
+/// warming up (e.g., a stuck mixing valve or a failed burner) and returns
+/// the reason. A pipe that warms up clears an earlier fault.
+fn check_pipe_warming(
+    config: &HeatingConfiguration,
+    conn: &Arc<Mutex<Connection>>,
+    metrics: &Metrics,
+    now: DateTime<Utc>,
+    stove_on_since: DateTime<Utc>,
+) -> Result<Option<String>, NeuroheatError> {
+    let warmup = Duration::minutes(config.controller.pipe_warmup_minutes as i64);
+    if now.signed_duration_since(stove_on_since) < warmup {
+        return Ok(None);
+    }
+
+    // readings are ordered from the newest to the oldest
+    let temperatures = repo::get_temperatures_since(conn, "pipe", stove_on_since)?;
+    let (Some(latest), Some(initial)) = (temperatures.first(), temperatures.last()) else {
+        return Ok(None);
+    };
+
+    let rise = latest - initial;
+    if rise >= config.controller.min_pipe_temperature_rise {
+        metrics.set_stove_fault_active(StoveDecision::PipeNotWarming.as_str(), false);
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "Stove has been ON since {} but the pipe temperature rose by {:.1}°C only ({:.1}°C -> {:.1}°C).",
+        stove_on_since, rise, initial, latest
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayController;
+    use crate::testing::{Harness, KITCHEN_PIN, OFFICE_PIN, STOVE_PIN};
+
+    fn set_temperatures(harness: &Harness, office: f32, kitchen: f32, pipe: f32) {
+        harness.sensor("office-sensor").set(Some(office));
+        harness.sensor("kitchen-sensor").set(Some(kitchen));
+        harness.sensor("pipe-sensor").set(Some(pipe));
+    }
+
+    fn stove_decisions(harness: &Harness, decision: StoveDecision) -> usize {
+        harness
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM stove_decisions WHERE decision = ?1",
+                [decision.as_str()],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn opens_the_valve_of_a_cold_room_only() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+
+        harness.update_valves().await.unwrap();
+
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+        assert_eq!(harness.stored_state("office"), Some(true));
+        assert!(!harness.relay(KITCHEN_PIN).read_state().unwrap());
+        assert_eq!(harness.relay(KITCHEN_PIN).writes(), 0);
+        assert_eq!(harness.stored_state("kitchen"), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_valve_within_the_hysteresis_band() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.9, 21.1, 30.0);
+        harness.read_temperatures(3).await;
+
+        harness.update_valves().await.unwrap();
+
+        assert_eq!(harness.relay(OFFICE_PIN).writes(), 0);
+        assert_eq!(harness.relay(KITCHEN_PIN).writes(), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_for_enough_readings() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 20.0, 30.0);
+        harness.read_temperatures(2).await;
+
+        harness.update_valves().await.unwrap();
+
+        assert_eq!(harness.relay(OFFICE_PIN).writes(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_valve_open_for_the_minimum_time() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+
+        harness.sensor("office-sensor").set(Some(22.0));
+        harness.read_temperatures(5).await;
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+
+        harness.read_temperatures(5).await;
+        harness.update_valves().await.unwrap();
+        assert!(!harness.relay(OFFICE_PIN).read_state().unwrap());
+        assert_eq!(harness.stored_state("office"), Some(false));
+    }
+
+    #[tokio::test]
+    async fn skips_rooms_with_a_failing_valve() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 20.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.relay(OFFICE_PIN).set_failing(true);
+
+        harness.update_valves().await.unwrap();
+
+        assert_eq!(harness.stored_state("office"), None);
+        assert_eq!(harness.stored_state("kitchen"), Some(true));
+    }
+
+    #[tokio::test]
+    async fn turns_the_stove_on_after_the_activation_delay() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+
+        harness.update_stove_state().await.unwrap();
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+
+        harness.read_temperatures(2).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(harness.relay(STOVE_PIN).read_state().unwrap());
+        assert_eq!(harness.stored_state("stove"), Some(true));
+        assert_eq!(stove_decisions(&harness, StoveDecision::On), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_stove_off_for_a_small_open_area() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 22.0, 20.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+
+        harness.read_temperatures(5).await;
+        harness.update_stove_state().await.unwrap();
+
+        assert!(harness.relay(KITCHEN_PIN).read_state().unwrap());
+        assert_eq!(harness.relay(STOVE_PIN).writes(), 0);
+    }
+
+    #[tokio::test]
+    async fn cuts_the_stove_until_the_pipe_cools_down() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+        harness.read_temperatures(2).await;
+        harness.update_stove_state().await.unwrap();
+
+        harness.sensor("pipe-sensor").set(Some(56.0));
+        harness.read_temperatures(1).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+        assert_eq!(stove_decisions(&harness, StoveDecision::Overheat), 1);
+
+        harness.sensor("pipe-sensor").set(Some(52.0));
+        harness.read_temperatures(1).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+
+        harness.sensor("pipe-sensor").set(Some(49.0));
+        harness.read_temperatures(1).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(harness.relay(STOVE_PIN).read_state().unwrap());
+    }
+
+    #[tokio::test]
+    async fn turns_the_stove_off_when_the_pipe_does_not_warm_up() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+        harness.read_temperatures(2).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(harness.relay(STOVE_PIN).read_state().unwrap());
+
+        harness.read_temperatures(30).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+        assert_eq!(stove_decisions(&harness, StoveDecision::PipeNotWarming), 1);
+        assert_eq!(harness.metrics.active_stove_faults(), ["pipe_not_warming"]);
+
+        // The stove is tried again after the warm-up time.
+        harness.read_temperatures(5).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+        harness.read_temperatures(26).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(harness.relay(STOVE_PIN).read_state().unwrap());
+
+        // A pipe warming up in the next run clears the fault.
+        harness.sensor("pipe-sensor").set(Some(40.0));
+        harness.read_temperatures(31).await;
+        harness.update_stove_state().await.unwrap();
+        assert!(harness.relay(STOVE_PIN).read_state().unwrap());
+        assert_eq!(stove_decisions(&harness, StoveDecision::PipeNotWarming), 1);
+        assert!(harness.metrics.active_stove_faults().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_not_report_a_warming_pipe() {
+        let harness = Harness::new();
+        set_temperatures(&harness, 20.0, 22.0, 30.0);
+        harness.read_temperatures(3).await;
+        harness.update_valves().await.unwrap();
+        harness.read_temperatures(2).await;
+        harness.update_stove_state().await.unwrap();
+
+        harness.sensor("pipe-sensor").set(Some(40.0));
+        harness.read_temperatures(30).await;
+        harness.update_stove_state().await.unwrap();
+
+        assert_eq!(stove_decisions(&harness, StoveDecision::PipeNotWarming), 0);
+    }
+}
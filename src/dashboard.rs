@@ -0,0 +1,36 @@
+use warp::Filter;
+
+// The assets are compiled into the binary, so the dashboard works without
+// any files next to it. Charts are drawn by the browser from the history API.
+const INDEX_HTML: &str = include_str!("../assets/dashboard/index.html");
+const DASHBOARD_JS: &str = include_str!("../assets/dashboard/dashboard.js");
+const DASHBOARD_CSS: &str = include_str!("../assets/dashboard/dashboard.css");
+
+/// Serves the dashboard at `/` and its assets under `/dashboard`.
+pub fn routes() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let index = warp::path::end()
+        .and(warp::get())
+        .map(|| asset(INDEX_HTML, "text/html; charset=utf-8"));
+
+    let script = warp::path!("dashboard" / "dashboard.js")
+        .and(warp::get())
+        .map(|| asset(DASHBOARD_JS, "text/javascript; charset=utf-8"));
+
+    let stylesheet = warp::path!("dashboard" / "dashboard.css")
+        .and(warp::get())
+        .map(|| asset(DASHBOARD_CSS, "text/css; charset=utf-8"));
+
+    index.or(script).unify().or(stylesheet).unify()
+}
+
+fn asset(body: &'static str, content_type: &'static str) -> warp::reply::Response {
+    use warp::Reply;
+
+    warp::reply::with_header(
+        warp::reply::with_header(body, "Content-Type", content_type),
+        // revalidate after upgrades, but not on every page load
+        "Cache-Control",
+        "public, max-age=3600",
+    )
+    .into_response()
+}
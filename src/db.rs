@@ -1,9 +1,11 @@
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags};
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 
+use crate::controller::OUTDOOR_KEY;
 use crate::error::NeuroheatError;
 use crate::heating_configuration::HeatingConfiguration;
+use crate::migrations;
 
 pub fn open(path: String) -> Connection {
     match Connection::open(path) {
@@ -15,6 +17,44 @@ pub fn open(path: String) -> Connection {
     }
 }
 
+/// Opens an existing database without the ability to change it (e.g., the
+/// production database read by a replay).
+pub fn open_read_only(path: &str) -> Result<Connection, NeuroheatError> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| {
+        let err_msg = format!("Failed to open database {}: {}", path, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::DatabaseError(err_msg)
+    })
+}
+
+/// Opens a database that must not contain anything yet (e.g., the output of
+/// a simulation), so simulated data is never mixed into a real database.
+pub fn open_empty(path: &str) -> Result<Connection, NeuroheatError> {
+    let conn = Connection::open(path).map_err(|e| {
+        let err_msg = format!("Failed to open database {}: {}", path, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::DatabaseError(err_msg)
+    })?;
+
+    let objects: i64 = conn
+        .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))
+        .map_err(|e| {
+            let err_msg = format!("Failed to read database {}: {}", path, e);
+            log::error!("{}", err_msg);
+            NeuroheatError::DatabaseError(err_msg)
+        })?;
+    if objects > 0 {
+        let err_msg = format!(
+            "Database {} is not empty. Use a new file for the simulated data.",
+            path
+        );
+        log::error!("{}", err_msg);
+        return Err(NeuroheatError::ValidationError(err_msg));
+    }
+
+    Ok(conn)
+}
+
 pub fn with_locked_connection<F, T>(
     conn: &Arc<Mutex<Connection>>,
     f: F,
@@ -24,52 +64,18 @@ where
 {
     let conn = conn.lock().map_err(|e| {
         let err_msg = format!("Failed to lock connection: {}", e);
-        Box::<dyn Error + Send + Sync>::from(err_msg)
+        Box::<dyn Error + Send + Sync>::from(NeuroheatError::DatabaseUnavailable(err_msg))
     })?;
-    f(&conn).map_err(|e| Box::<dyn Error + Send + Sync>::from(e))
+    f(&conn).map_err(Box::<dyn Error + Send + Sync>::from)
 }
 
 pub fn init(
     conn: &Arc<Mutex<Connection>>,
     config: &HeatingConfiguration,
 ) -> Result<(), NeuroheatError> {
-    with_locked_connection(conn, |conn| {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS labels (
-              key TEXT PRIMARY KEY,
-              label TEXT NOT NULL
-          )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS temperatures (
-              id INTEGER PRIMARY KEY,
-              key TEXT NOT NULL,
-              temperature REAL NOT NULL,
-              expected_temperature REAL,
-              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc')),
-              FOREIGN KEY(key) REFERENCES labels(key)
-          )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS temperatures_key_timestamp_idx ON temperatures (key, timestamp)",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS states (
-              id INTEGER PRIMARY KEY,
-              key TEXT NOT NULL,
-              state INTEGER NOT NULL,
-              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc')),
-              FOREIGN KEY(key) REFERENCES labels(key)
-          )",
-            [],
-        )?;
+    migrate(conn)?;
 
+    with_locked_connection(conn, |conn| {
         for room in &config.rooms {
             conn.execute(
                 "INSERT OR IGNORE INTO labels (key, label) VALUES (?1, ?2)",
@@ -83,6 +89,13 @@ pub fn init(
             params!["pipe", "Heating Pipe"],
         )?;
 
+        if config.outdoor_sensor_id.is_some() {
+            conn.execute(
+                "INSERT OR IGNORE INTO labels (key, label) VALUES (?1, ?2)",
+                params![OUTDOOR_KEY, "Outdoor"],
+            )?;
+        }
+
         // Insert a value for the stove as "Stove"
         conn.execute(
             "INSERT OR IGNORE INTO labels (key, label) VALUES (?1, ?2)",
@@ -94,6 +107,40 @@ pub fn init(
     .map_err(|e| {
         let err_msg = format!("Failed to initialize database: {}", e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
+
+/// Brings the database schema up to date. Refuses to touch databases
+/// created by a newer version of the application.
+pub fn migrate(conn: &Arc<Mutex<Connection>>) -> Result<usize, NeuroheatError> {
+    let conn = conn.lock().map_err(|e| {
+        let err_msg = format!("Failed to lock connection: {}", e);
+        log::error!("{}", err_msg);
+        NeuroheatError::DatabaseUnavailable(err_msg)
+    })?;
+
+    migrations::migrate(&conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_only_an_empty_database() {
+        let path = std::env::temp_dir().join(format!("neuroheat-empty-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let conn = open_empty(path).unwrap();
+        conn.execute("CREATE TABLE labels (key TEXT)", []).unwrap();
+        drop(conn);
+
+        assert!(matches!(
+            open_empty(path),
+            Err(NeuroheatError::ValidationError(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
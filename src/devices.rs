@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::heating_configuration::RelayConfiguration;
+use crate::relay::{RelayController, VirtualRelayController};
+use crate::temperature_sensor::{TemperatureSensor, VirtualTemperatureSensor, DS18B20};
+
+/// Creates the sensors and relays referenced by a heating configuration.
+pub trait Devices {
+    fn temperature_sensor(&self, sensor_id: &str) -> Arc<dyn TemperatureSensor>;
+    fn relay(&self, pin: u8, relay: &RelayConfiguration) -> Arc<dyn RelayController>;
+}
+
+/// The 1-Wire sensors and GPIO relays of the Raspberry Pi.
+#[derive(Debug, Default)]
+pub struct HardwareDevices;
+
+impl Devices for HardwareDevices {
+    fn temperature_sensor(&self, sensor_id: &str) -> Arc<dyn TemperatureSensor> {
+        Arc::new(DS18B20::new(sensor_id.to_string()))
+    }
+
+    fn relay(&self, pin: u8, relay: &RelayConfiguration) -> Arc<dyn RelayController> {
+        relay.controller(pin)
+    }
+}
+
+/// In-memory sensors and relays. The same sensor ID (or pin) always maps to
+/// the same device, so the devices handed to the configuration can be
+/// looked up and driven afterwards.
+#[derive(Debug, Default)]
+pub struct VirtualDevices {
+    sensors: Mutex<HashMap<String, Arc<VirtualTemperatureSensor>>>,
+    relays: Mutex<HashMap<u8, Arc<VirtualRelayController>>>,
+}
+
+impl VirtualDevices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sensor with the given ID.
+    pub fn sensor(&self, sensor_id: &str) -> Arc<VirtualTemperatureSensor> {
+        self.sensors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(sensor_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the relay driving the given pin.
+    pub fn relay_at(&self, pin: u8) -> Arc<VirtualRelayController> {
+        self.relays
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(pin)
+            .or_default()
+            .clone()
+    }
+}
+
+impl Devices for VirtualDevices {
+    fn temperature_sensor(&self, sensor_id: &str) -> Arc<dyn TemperatureSensor> {
+        self.sensor(sensor_id)
+    }
+
+    fn relay(&self, pin: u8, _relay: &RelayConfiguration) -> Arc<dyn RelayController> {
+        self.relay_at(pin)
+    }
+}
@@ -1,12 +1,21 @@
 use std::fmt;
 use std::io;
 
+use rusqlite::ErrorCode;
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum NeuroheatError {
     ConfigurationError(String),
     DatabaseError(String),
+    /// The database is busy or locked by another connection, or its lock
+    /// was poisoned. Unlike other database errors, these are expected to
+    /// go away.
+    DatabaseUnavailable(String),
+    NotFoundError(String),
     RelayError(String),
     SensorError(String),
+    ValidationError(String),
 }
 
 impl fmt::Display for NeuroheatError {
@@ -14,14 +23,43 @@ impl fmt::Display for NeuroheatError {
         match self {
             NeuroheatError::ConfigurationError(msg) => write!(f, "Configuration error: {}", msg),
             NeuroheatError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+            NeuroheatError::DatabaseUnavailable(msg) => {
+                write!(f, "Database unavailable: {}", msg)
+            }
+            NeuroheatError::NotFoundError(msg) => write!(f, "Not found: {}", msg),
             NeuroheatError::RelayError(msg) => write!(f, "Relay error: {}", msg),
             NeuroheatError::SensorError(msg) => write!(f, "Sensor error: {}", msg),
+            NeuroheatError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
         }
     }
 }
 
 impl std::error::Error for NeuroheatError {}
 
+impl NeuroheatError {
+    /// Returns the error for a failed database call: `DatabaseUnavailable`
+    /// if the database was busy or locked, or its lock was poisoned, and
+    /// `DatabaseError` otherwise.
+    pub fn database(err_msg: String, error: &(dyn std::error::Error + 'static)) -> Self {
+        let unavailable = match error.downcast_ref::<rusqlite::Error>() {
+            Some(error) => matches!(
+                error.sqlite_error_code(),
+                Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+            ),
+            None => matches!(
+                error.downcast_ref::<NeuroheatError>(),
+                Some(NeuroheatError::DatabaseUnavailable(_))
+            ),
+        };
+
+        if unavailable {
+            NeuroheatError::DatabaseUnavailable(err_msg)
+        } else {
+            NeuroheatError::DatabaseError(err_msg)
+        }
+    }
+}
+
 impl From<io::Error> for NeuroheatError {
     fn from(error: io::Error) -> Self {
         NeuroheatError::SensorError(error.to_string())
@@ -30,6 +68,6 @@ impl From<io::Error> for NeuroheatError {
 
 impl From<rusqlite::Error> for NeuroheatError {
     fn from(error: rusqlite::Error) -> Self {
-        NeuroheatError::DatabaseError(error.to_string())
+        NeuroheatError::database(error.to_string(), &error)
     }
 }
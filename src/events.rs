@@ -0,0 +1,161 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::temperature_override::TemperatureOverride;
+
+/// The number of events kept for slow subscribers before they start
+/// missing events.
+const EVENT_CAPACITY: usize = 64;
+
+/// A change recorded by the background jobs or the API.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A temperature reading was stored.
+    Temperature {
+        key: String,
+        temperature: f32,
+        expected_temperature: Option<f32>,
+        timestamp: DateTime<Utc>,
+    },
+    /// The state of a valve or the stove was stored.
+    RelayState {
+        key: String,
+        heating_enabled: bool,
+        timestamp: DateTime<Utc>,
+    },
+    /// The stove controller made a decision.
+    StoveDecision {
+        decision: &'static str,
+        reason: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// An override was created or replaced.
+    OverrideSet {
+        #[serde(flatten)]
+        temperature_override: TemperatureOverride,
+    },
+    /// An override was cancelled.
+    OverrideCancelled {
+        key: String,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+impl Event {
+    /// The name of the event as sent to Server-Sent Events clients.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Temperature { .. } => "temperature",
+            Event::RelayState { .. } => "relay_state",
+            Event::StoveDecision { .. } => "stove_decision",
+            Event::OverrideSet { .. } => "override_set",
+            Event::OverrideCancelled { .. } => "override_cancelled",
+        }
+    }
+}
+
+/// Publishes events to all current subscribers.
+#[derive(Debug)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Events { sender }
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        // fails only when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    pub fn temperature(
+        &self,
+        key: &str,
+        temperature: f32,
+        expected_temperature: Option<f32>,
+        timestamp: DateTime<Utc>,
+    ) {
+        self.publish(Event::Temperature {
+            key: key.to_string(),
+            temperature,
+            expected_temperature,
+            timestamp,
+        });
+    }
+
+    pub fn relay_state(&self, key: &str, heating_enabled: bool, timestamp: DateTime<Utc>) {
+        self.publish(Event::RelayState {
+            key: key.to_string(),
+            heating_enabled,
+            timestamp,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    fn drain(receiver: &mut broadcast::Receiver<Event>) -> Vec<Event> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn jobs_publish_their_changes() {
+        let harness = Harness::new();
+        let mut receiver = harness.events.subscribe();
+        harness.sensor("office-sensor").set(Some(19.0));
+        harness.sensor("kitchen-sensor").set(Some(22.0));
+        harness.sensor("pipe-sensor").set(Some(30.0));
+
+        harness.read_temperatures(5).await;
+        let readings = drain(&mut receiver);
+        assert_eq!(readings.len(), 15);
+        assert!(readings.iter().all(|event| event.name() == "temperature"));
+        assert!(matches!(
+            &readings[0],
+            Event::Temperature { key, temperature, .. } if key == "pipe" && *temperature == 30.0
+        ));
+        assert!(matches!(
+            &readings[1],
+            Event::Temperature { key, expected_temperature: Some(expected), .. }
+                if key == "office" && *expected == 21.0
+        ));
+
+        harness.update_valves().await.unwrap();
+        let valves = drain(&mut receiver);
+        assert!(valves.iter().any(|event| matches!(
+            event,
+            Event::RelayState { key, heating_enabled: true, .. } if key == "office"
+        )));
+
+        // The stove turns on once the valve has been open for a while.
+        harness.read_temperatures(5).await;
+        drain(&mut receiver);
+        harness.update_stove_state().await.unwrap();
+        let stove = drain(&mut receiver);
+        assert!(stove
+            .iter()
+            .any(|event| matches!(event, Event::StoveDecision { decision: "on", .. })));
+        assert!(stove.iter().any(|event| matches!(
+            event,
+            Event::RelayState { key, heating_enabled: true, .. } if key == "stove"
+        )));
+    }
+}
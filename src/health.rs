@@ -0,0 +1,342 @@
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::controller::OUTDOOR_KEY;
+use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
+use crate::repo;
+use crate::scheduler;
+
+/// The overall health of the application.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// All checks pass.
+    Ok,
+    /// Only non-critical checks (e.g., the outdoor sensor) fail.
+    Degraded,
+    /// At least one critical check fails.
+    Failing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: Status,
+    pub version: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub uptime_seconds: i64,
+    pub database: DatabaseHealth,
+    pub jobs: BTreeMap<&'static str, JobHealth>,
+    pub sensors: BTreeMap<String, SensorHealth>,
+    pub relays: BTreeMap<String, RelayHealth>,
+    pub stove: StoveHealth,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseHealth {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobHealth {
+    pub ok: bool,
+    pub expected_interval_seconds: Option<i64>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub runs: u64,
+    pub failures: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SensorHealth {
+    pub ok: bool,
+    /// Whether a failure of the sensor makes the application unhealthy.
+    pub critical: bool,
+    pub last_reading: Option<DateTime<Utc>>,
+    pub age_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelayHealth {
+    pub ok: bool,
+    pub heating_enabled: Option<bool>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoveHealth {
+    pub ok: bool,
+    /// The faults that persist (e.g., the pipe not warming up).
+    pub faults: Vec<&'static str>,
+}
+
+impl Health {
+    /// Runs all checks at the given time.
+    pub fn check(
+        config: &HeatingConfiguration,
+        conn: &Arc<Mutex<Connection>>,
+        metrics: &Metrics,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let database = match repo::check_writable(conn) {
+            Ok(()) => DatabaseHealth {
+                ok: true,
+                error: None,
+            },
+            Err(e) => DatabaseHealth {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        };
+
+        let started_at = metrics.started_at();
+        let job_stats = metrics.job_stats();
+        let jobs = scheduler::jobs(config)
+            .into_iter()
+            .map(|(job, expression)| {
+                let stats = job_stats.get(job).cloned().unwrap_or_default();
+                let interval = scheduler::job_interval(expression, now);
+                let ok = !scheduler::is_overdue(
+                    expression,
+                    stats.last_success.unwrap_or(started_at),
+                    config.health.max_missed_job_runs,
+                    now,
+                );
+                let health = JobHealth {
+                    ok,
+                    expected_interval_seconds: interval.map(|interval| interval.num_seconds()),
+                    last_success: stats.last_success,
+                    runs: stats.runs,
+                    failures: stats.failures,
+                };
+                (job, health)
+            })
+            .collect();
+
+        let max_age = Duration::minutes(config.health.max_reading_age_minutes as i64);
+        let mut sensors = BTreeMap::new();
+        let sensor_keys = config
+            .rooms
+            .iter()
+            .map(|room| (room.key.as_str(), true))
+            .chain(std::iter::once(("pipe", true)))
+            .chain(
+                config
+                    .outdoor_sensor_id
+                    .as_ref()
+                    .map(|_| (OUTDOOR_KEY, false)),
+            );
+        for (key, critical) in sensor_keys {
+            let last_reading = match repo::get_latest_reading(conn, key) {
+                Ok(reading) => reading.map(|(_, timestamp)| timestamp),
+                Err(e) => {
+                    log::error!("Failed to get latest reading for key {}: {}", key, e);
+                    None
+                }
+            };
+            let age = last_reading.map(|timestamp| now.signed_duration_since(timestamp));
+            let health = SensorHealth {
+                ok: age.is_some_and(|age| age <= max_age),
+                critical,
+                last_reading,
+                age_seconds: age.map(|age| age.num_seconds()),
+            };
+            sensors.insert(key.to_string(), health);
+        }
+
+        let relay_readers = config
+            .rooms
+            .iter()
+            .map(|room| (room.key.as_str(), room.valve_reader.as_ref()))
+            .chain(std::iter::once(("stove", config.stove_reader.as_ref())));
+        let relays = relay_readers
+            .map(|(key, reader)| {
+                let state = match reader {
+                    Some(reader) => reader.read_state().map_err(|e| e.to_string()),
+                    None => Err("No relay controller configured".to_string()),
+                };
+                let health = RelayHealth {
+                    ok: state.is_ok(),
+                    heating_enabled: state.as_ref().ok().copied(),
+                    error: state.err(),
+                };
+                (key.to_string(), health)
+            })
+            .collect();
+
+        let faults = metrics.active_stove_faults();
+        let stove = StoveHealth {
+            ok: faults.is_empty(),
+            faults,
+        };
+
+        let mut health = Health {
+            status: Status::Ok,
+            version: env!("CARGO_PKG_VERSION"),
+            started_at,
+            uptime_seconds: now.signed_duration_since(started_at).num_seconds(),
+            database,
+            jobs,
+            sensors,
+            relays,
+            stove,
+        };
+        health.status = health.evaluate();
+        health
+    }
+
+    fn evaluate(&self) -> Status {
+        let critical_ok = self.database.ok
+            && self.jobs.values().all(|job| job.ok)
+            && self
+                .sensors
+                .values()
+                .all(|sensor| sensor.ok || !sensor.critical)
+            && self.relays.values().all(|relay| relay.ok)
+            && self.stove.ok;
+
+        if !critical_ok {
+            Status::Failing
+        } else if self.sensors.values().any(|sensor| !sensor.ok) {
+            Status::Degraded
+        } else {
+            Status::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::testing::{with_settings, Harness, CONFIG};
+
+    /// Returns a harness whose room and pipe sensors report temperatures.
+    fn harness(json: &str) -> Harness {
+        let harness = Harness::with_config(json);
+        for sensor in ["office-sensor", "kitchen-sensor"] {
+            harness.sensor(sensor).set(Some(21.0));
+        }
+        harness.sensor("pipe-sensor").set(Some(40.0));
+        harness
+    }
+
+    /// Reads all sensors and records a successful run of every job but the
+    /// given ones.
+    async fn run_jobs(harness: &Harness, except: &[&str]) {
+        harness.read_temperatures(1).await;
+        for (job, _) in scheduler::jobs(&harness.config) {
+            if !except.contains(&job) {
+                harness
+                    .metrics
+                    .record_job_run(job, std::time::Duration::ZERO, true);
+            }
+        }
+    }
+
+    fn check(harness: &Harness) -> Health {
+        Health::check(
+            &harness.config,
+            &harness.conn,
+            &harness.metrics,
+            harness.config.clock.now(),
+        )
+    }
+
+    #[tokio::test]
+    async fn is_ok_while_all_checks_pass() {
+        let harness = harness(CONFIG);
+        run_jobs(&harness, &[]).await;
+
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Ok);
+        assert!(health.database.ok);
+        assert!(health.sensors.values().all(|sensor| sensor.ok));
+        assert_eq!(health.relays.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stale_sensors_degrade_or_fail() {
+        let harness = harness(&with_settings(
+            CONFIG,
+            r#""outdoor_sensor_id": "outdoor-sensor""#,
+        ));
+        run_jobs(&harness, &[]).await;
+
+        // The outdoor sensor has never been read.
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Degraded);
+        assert!(!health.sensors[OUTDOOR_KEY].ok);
+        assert!(!health.sensors[OUTDOOR_KEY].critical);
+
+        harness.sensor("outdoor-sensor").set(Some(5.0));
+        run_jobs(&harness, &[]).await;
+        assert_eq!(check(&harness).status, Status::Ok);
+
+        harness.sensor("outdoor-sensor").set(None);
+        harness.advance(15);
+        harness.sensor("office-sensor").set(None);
+        run_jobs(&harness, &[]).await;
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Failing);
+        assert!(!health.sensors["office"].ok);
+        assert_eq!(health.sensors["office"].age_seconds, Some(16 * 60));
+        assert!(health.sensors["kitchen"].ok);
+    }
+
+    #[tokio::test]
+    async fn fails_while_a_job_does_not_succeed() {
+        let harness = harness(CONFIG);
+        run_jobs(&harness, &[]).await;
+        harness.advance(5);
+        run_jobs(&harness, &[scheduler::VALVE_CONTROLLER_JOB]).await;
+        assert_eq!(check(&harness).status, Status::Ok);
+
+        harness.advance(10);
+        run_jobs(&harness, &[scheduler::VALVE_CONTROLLER_JOB]).await;
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Failing);
+        let job = &health.jobs[scheduler::VALVE_CONTROLLER_JOB];
+        assert!(!job.ok);
+        assert_eq!(job.runs, 1);
+        assert!(health.jobs[scheduler::TEMPERATURE_JOB].ok);
+    }
+
+    #[tokio::test]
+    async fn fails_while_a_stove_fault_persists() {
+        let harness = harness(CONFIG);
+        run_jobs(&harness, &[]).await;
+
+        harness
+            .metrics
+            .set_stove_fault_active("pipe_not_warming", true);
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Failing);
+        assert_eq!(health.stove.faults, vec!["pipe_not_warming"]);
+
+        harness
+            .metrics
+            .set_stove_fault_active("pipe_not_warming", false);
+        assert_eq!(check(&harness).status, Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn fails_while_the_database_is_not_writable() {
+        let harness = harness(CONFIG);
+        run_jobs(&harness, &[]).await;
+
+        db::with_locked_connection(&harness.conn, |conn| {
+            conn.execute_batch("PRAGMA query_only = ON")
+        })
+        .unwrap();
+        let health = check(&harness);
+        assert_eq!(health.status, Status::Failing);
+        assert!(!health.database.ok);
+        assert!(health.database.error.is_some());
+        // Readings can still be checked.
+        assert!(health.sensors.values().all(|sensor| sensor.ok));
+    }
+}
@@ -1,13 +1,22 @@
+use crate::clock::{self, Clock};
+use crate::devices::{Devices, HardwareDevices};
 use crate::error::NeuroheatError;
-use crate::relay::{GPIOController, RelayController};
-use crate::temperature_sensor::{TemperatureSensor, DS18B20};
+use crate::relay::{GPIOCharDeviceController, GPIOController, RelayController};
+use crate::scheduler;
+use crate::temperature_override::TemperatureOverride;
+use crate::temperature_sensor::TemperatureSensor;
+use crate::valve_strategy::{PiStrategy, ThresholdStrategy, ValveStrategy};
 
-use chrono::{Local, Timelike};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, Timelike, Utc, Weekday};
 use serde::Deserialize;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::sync::Arc;
 
+/// The largest hysteresis accepted in the configuration. Larger values
+/// are most likely a typo and would keep valves in one state for hours.
+const MAX_HYSTERESIS: f32 = 2.0;
+
 /// Represents a room in the house.
 #[derive(Debug, Deserialize)]
 pub struct Room {
@@ -20,8 +29,14 @@ pub struct Room {
     /// The temperature sensor associated with the room.
     #[serde(skip)]
     pub sensor: Option<Arc<dyn TemperatureSensor>>,
+    /// The plausible readings of the room sensor.
+    #[serde(default = "SensorRange::room")]
+    pub sensor_range: SensorRange,
     /// The GPIO pin controlling the valve for the floor heating for the room.
     pub valve_pin: u8,
+    /// How the valve relay is driven.
+    #[serde(default)]
+    pub valve_relay: RelayConfiguration,
     /// The area of the room in square meters.
     pub area: f32,
     /// The temperature schedule for the room.
@@ -29,19 +44,213 @@ pub struct Room {
     /// The relay reader for the valve.
     #[serde(skip)]
     pub valve_reader: Option<Arc<dyn RelayController>>,
+    /// Overrides the default hysteresis for the room.
+    pub hysteresis: Option<f32>,
+    /// Overrides the default minimum time the valve stays open.
+    pub min_valve_on_minutes: Option<u32>,
+    /// Overrides the default minimum time the valve stays closed.
+    pub min_valve_off_minutes: Option<u32>,
+    /// Adjusts the scheduled temperature based on the outdoor temperature.
+    pub weather_compensation: Option<WeatherCompensation>,
+    /// How the valve state follows the room temperature.
+    #[serde(default)]
+    pub valve_strategy: ValveStrategyConfiguration,
+}
+
+/// A linear weather-compensation curve. The scheduled temperature is
+/// raised by `slope` for every degree the outdoor temperature is below
+/// `reference_temperature` (and lowered when it is above), limited to
+/// `max_adjustment` in either direction.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WeatherCompensation {
+    /// The outdoor temperature at which the schedule applies unchanged.
+    pub reference_temperature: f32,
+    /// The change of the expected temperature per degree of outdoor temperature.
+    pub slope: f32,
+    /// The largest adjustment of the expected temperature.
+    pub max_adjustment: f32,
+}
+
+impl WeatherCompensation {
+    /// Returns the adjustment of the expected temperature for the given
+    /// outdoor temperature.
+    pub fn adjustment(&self, outdoor_temperature: f32) -> f32 {
+        ((self.reference_temperature - outdoor_temperature) * self.slope)
+            .clamp(-self.max_adjustment, self.max_adjustment)
+    }
+}
+
+/// How the valve of a room is controlled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ValveStrategyConfiguration {
+    /// Opens the valve below the expected temperature minus the hysteresis
+    /// and closes it above the expected temperature plus the hysteresis.
+    #[default]
+    Threshold,
+    /// Opens the valve for a part of every cycle (time-proportional
+    /// control), with the part set by a PI controller.
+    Pi(PiConfiguration),
+}
+
+impl ValveStrategyConfiguration {
+    /// Builds the strategy deciding on the valve state.
+    pub fn strategy(&self) -> Box<dyn ValveStrategy> {
+        match self {
+            ValveStrategyConfiguration::Threshold => Box::new(ThresholdStrategy),
+            ValveStrategyConfiguration::Pi(pi) => Box::new(PiStrategy::new(*pi)),
+        }
+    }
+}
+
+/// The gains and the cycle length of a PI valve controller. The duty cycle
+/// is the proportional gain times the current error plus the integral gain
+/// times the accumulated error (in °C·h), limited to 0-100%.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PiConfiguration {
+    /// The length of a valve cycle. The duty cycle is updated at the start
+    /// of every cycle.
+    pub cycle_minutes: u32,
+    /// The duty cycle per °C below the expected temperature.
+    pub proportional_gain: f32,
+    /// The duty cycle per °C·h of accumulated error.
+    pub integral_gain: f32,
+}
+
+impl Default for PiConfiguration {
+    fn default() -> Self {
+        PiConfiguration {
+            cycle_minutes: 30,
+            proportional_gain: 0.5,
+            integral_gain: 0.1,
+        }
+    }
 }
 
 /// Represents a temperature schedule for a room.
+///
+/// Boundaries can be given either as whole hours (`start_hour`/`end_hour`)
+/// or as `HH:MM` strings (`start`/`end`). The optional `days` list limits
+/// the entry to specific weekdays (e.g., `"mon"`, `"sunday"`) or sets of
+/// them (`"workdays"`, `"weekend"`); without it, the entry applies every day.
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "RawTemperatureSchedule")]
 pub struct TemperatureSchedule {
-    /// The start hour of the schedule (0-23).
-    pub start_hour: u8,
-    /// The end hour of the schedule (0-23).
-    pub end_hour: u8,
+    /// The weekdays the schedule applies to (every day if empty).
+    pub days: Vec<Weekday>,
+    /// The start of the schedule in minutes since midnight (inclusive).
+    pub start_minute: u16,
+    /// The end of the schedule in minutes since midnight (exclusive).
+    pub end_minute: u16,
     /// The target temperature during the schedule period.
     pub temperature: f32,
 }
 
+/// The temperature schedule as written in the configuration file.
+#[derive(Debug, Deserialize)]
+struct RawTemperatureSchedule {
+    #[serde(default)]
+    days: Vec<String>,
+    start_hour: Option<u8>,
+    end_hour: Option<u8>,
+    start: Option<String>,
+    end: Option<String>,
+    temperature: f32,
+}
+
+impl TryFrom<RawTemperatureSchedule> for TemperatureSchedule {
+    type Error = String;
+
+    fn try_from(raw: RawTemperatureSchedule) -> Result<Self, Self::Error> {
+        let start_minute = match (raw.start_hour, raw.start) {
+            (Some(hour), None) => hours_to_minutes(hour)?,
+            (None, Some(time)) => parse_time_of_day(&time)?,
+            _ => return Err("exactly one of start_hour and start is required".to_string()),
+        };
+        let end_minute = match (raw.end_hour, raw.end) {
+            (Some(hour), None) => hours_to_minutes(hour)?,
+            (None, Some(time)) => parse_time_of_day(&time)?,
+            _ => return Err("exactly one of end_hour and end is required".to_string()),
+        };
+
+        if start_minute >= end_minute {
+            return Err(format!(
+                "schedule start ({}) must be before its end ({})",
+                format_time_of_day(start_minute),
+                format_time_of_day(end_minute)
+            ));
+        }
+
+        let mut days = Vec::new();
+        for day in &raw.days {
+            for weekday in parse_days(day)? {
+                if !days.contains(&weekday) {
+                    days.push(weekday);
+                }
+            }
+        }
+
+        Ok(TemperatureSchedule {
+            days,
+            start_minute,
+            end_minute,
+            temperature: raw.temperature,
+        })
+    }
+}
+
+impl TemperatureSchedule {
+    /// Checks whether the schedule covers the given local date and time.
+    pub fn covers(&self, datetime: &NaiveDateTime) -> bool {
+        let minute = (datetime.hour() * 60 + datetime.minute()) as u16;
+        let day_matches = self.days.is_empty() || self.days.contains(&datetime.weekday());
+
+        day_matches && minute >= self.start_minute && minute < self.end_minute
+    }
+}
+
+fn hours_to_minutes(hour: u8) -> Result<u16, String> {
+    if hour > 24 {
+        return Err(format!("invalid hour {} (expected 0-24)", hour));
+    }
+    Ok(hour as u16 * 60)
+}
+
+/// Parses `HH:MM` into minutes since midnight. `24:00` denotes the end of the day.
+fn parse_time_of_day(time: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid time {:?} (expected HH:MM)", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u16 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes != 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+fn format_time_of_day(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn parse_days(day: &str) -> Result<Vec<Weekday>, String> {
+    match day.to_lowercase().as_str() {
+        "workdays" | "weekdays" => Ok(vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ]),
+        "weekend" => Ok(vec![Weekday::Sat, Weekday::Sun]),
+        other => other
+            .parse::<Weekday>()
+            .map(|weekday| vec![weekday])
+            .map_err(|_| format!("invalid day {:?}", day)),
+    }
+}
+
 /// Represents the heating configuration for the entire system.
 #[derive(Debug, Deserialize)]
 pub struct HeatingConfiguration {
@@ -49,51 +258,886 @@ pub struct HeatingConfiguration {
     pub rooms: Vec<Room>,
     /// The GPIO pin controlling the stove.
     pub stove_pin: u8,
+    /// How the stove relay is driven.
+    #[serde(default)]
+    pub stove_relay: RelayConfiguration,
     /// The sensor ID for the heating pipe.
     pub pipe_sensor_id: String,
     /// The temperature sensor for the heating pipe.
     #[serde(skip)]
     pub pipe_sensor: Option<Arc<dyn TemperatureSensor>>,
+    /// The plausible readings of the pipe sensor.
+    #[serde(default = "SensorRange::pipe")]
+    pub pipe_sensor_range: SensorRange,
+    /// The sensor ID for the (optional) outdoor sensor.
+    pub outdoor_sensor_id: Option<String>,
+    /// The temperature sensor outside of the house.
+    #[serde(skip)]
+    pub outdoor_sensor: Option<Arc<dyn TemperatureSensor>>,
+    /// The plausible readings of the outdoor sensor.
+    #[serde(default = "SensorRange::outdoor")]
+    pub outdoor_sensor_range: SensorRange,
     /// The relay reader for the stove.
     #[serde(skip)]
     pub stove_reader: Option<Arc<dyn RelayController>>,
+    /// The tuning of the heating controller.
+    #[serde(default)]
+    pub controller: ControllerConfiguration,
+    /// The schedules of the background jobs.
+    #[serde(default)]
+    pub scheduler: SchedulerConfiguration,
+    /// The relay states applied on startup and shutdown.
+    #[serde(default)]
+    pub safe_state: SafeStateConfiguration,
+    /// The thresholds of the health endpoint.
+    #[serde(default)]
+    pub health: HealthConfiguration,
+    /// How long to keep raw and aggregated readings.
+    #[serde(default)]
+    pub retention: RetentionConfiguration,
+    /// The source of the current time.
+    #[serde(skip, default = "clock::system")]
+    pub clock: Arc<dyn Clock>,
+}
+
+/// How long readings are kept at each resolution. Raw readings are rolled
+/// into hourly aggregates, which are later rolled into daily aggregates
+/// that are kept forever.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfiguration {
+    /// How many days to keep raw readings and relay states.
+    pub raw_days: u32,
+    /// How many days to keep hourly aggregates.
+    pub hourly_days: u32,
+}
+
+impl Default for RetentionConfiguration {
+    fn default() -> Self {
+        RetentionConfiguration {
+            raw_days: 30,
+            hourly_days: 365,
+        }
+    }
+}
+
+/// The thresholds used by the health endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HealthConfiguration {
+    /// How old the latest temperature reading of a sensor may be.
+    pub max_reading_age_minutes: u32,
+    /// How many consecutive runs a job may miss (or fail) before it is
+    /// reported as unhealthy.
+    pub max_missed_job_runs: u32,
+    /// How long a job run may take before the job is considered hung, which
+    /// stops the systemd watchdog pings.
+    pub max_job_run_seconds: u32,
+}
+
+impl Default for HealthConfiguration {
+    fn default() -> Self {
+        HealthConfiguration {
+            max_reading_age_minutes: 15,
+            max_missed_job_runs: 2,
+            max_job_run_seconds: 60,
+        }
+    }
+}
+
+/// The relay states applied when the application starts (before the
+/// controller takes over) and when it stops. Defaults to all relays off,
+/// which matches what happens after a power loss.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SafeStateConfiguration {
+    /// The state of the stove.
+    pub stove: bool,
+    /// The state of all valves.
+    pub valves: bool,
+}
+
+/// The plausible readings of a temperature sensor. Readings outside of
+/// the range or changing faster than allowed are rejected.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SensorRange {
+    /// The lowest plausible temperature.
+    pub min: f32,
+    /// The highest plausible temperature.
+    pub max: f32,
+    /// The maximum plausible change (in °C per minute) since the previous
+    /// accepted reading.
+    #[serde(default)]
+    pub max_change_per_minute: Option<f32>,
+}
+
+impl SensorRange {
+    pub fn room() -> Self {
+        SensorRange {
+            min: 0.0,
+            max: 50.0,
+            max_change_per_minute: None,
+        }
+    }
+
+    pub fn pipe() -> Self {
+        SensorRange {
+            min: 0.0,
+            max: 90.0,
+            max_change_per_minute: None,
+        }
+    }
+
+    pub fn outdoor() -> Self {
+        SensorRange {
+            min: -50.0,
+            max: 60.0,
+            max_change_per_minute: None,
+        }
+    }
+
+    /// Checks the reading against the range and the previous accepted
+    /// reading. Returns the reason for rejecting it.
+    pub fn check(
+        &self,
+        temperature: f32,
+        previous: Option<(f32, DateTime<Utc>)>,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if !(self.min..=self.max).contains(&temperature) {
+            return Err(format!("out of range {:.1}-{:.1}°C", self.min, self.max));
+        }
+
+        if let (Some(max_change), Some((previous, at))) = (self.max_change_per_minute, previous) {
+            // avoid exaggerating the rate for readings taken shortly one after another
+            let minutes = (now.signed_duration_since(at).num_seconds() as f32 / 60.0).max(1.0);
+            let change = (temperature - previous).abs() / minutes;
+
+            if change > max_change {
+                return Err(format!(
+                    "changed by {:.2}°C/min since {:.1}°C at {} (max {:.2}°C/min)",
+                    change, previous, at, max_change
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if self.min >= self.max {
+            return Err(format!("{}.min must be lower than max", name));
+        }
+        if self
+            .max_change_per_minute
+            .is_some_and(|change| change <= 0.0)
+        {
+            return Err(format!("{}.max_change_per_minute must be positive", name));
+        }
+        Ok(())
+    }
+}
+
+/// The interface used to drive a relay's GPIO pin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayBackend {
+    /// The deprecated `/sys/class/gpio` export interface.
+    #[default]
+    Sysfs,
+    /// The GPIO character device (`/dev/gpiochipN`) line request API.
+    Cdev,
+}
+
+/// Represents how a relay is driven.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RelayConfiguration {
+    /// The interface used to drive the pin.
+    pub backend: RelayBackend,
+    /// The GPIO chip the pin belongs to (used by the `cdev` backend).
+    pub chip: String,
+    /// Whether the relay is switched on by a low pin level.
+    pub active_low: bool,
+}
+
+impl Default for RelayConfiguration {
+    fn default() -> Self {
+        RelayConfiguration {
+            backend: RelayBackend::Sysfs,
+            chip: "/dev/gpiochip0".to_string(),
+            active_low: false,
+        }
+    }
+}
+
+impl RelayConfiguration {
+    /// Builds the relay controller for the given pin.
+    pub fn controller(&self, pin: u8) -> Arc<dyn RelayController> {
+        match self.backend {
+            RelayBackend::Sysfs => Arc::new(GPIOController::new(pin, self.active_low)),
+            RelayBackend::Cdev => Arc::new(GPIOCharDeviceController::new(
+                self.chip.clone(),
+                pin,
+                self.active_low,
+            )),
+        }
+    }
+}
+
+/// Tuning of the heating controller. Rooms can override the valve settings.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ControllerConfiguration {
+    /// How far (in °C) the average temperature has to fall below the expected
+    /// temperature to open a valve, and rise above it to close the valve.
+    pub hysteresis: f32,
+    /// The minimum time a valve stays open before it can be closed.
+    pub min_valve_on_minutes: u32,
+    /// The minimum time a valve stays closed before it can be opened.
+    pub min_valve_off_minutes: u32,
+    /// The minimum area in square meters that a stove requires
+    /// when turning on heating. If valves are open for a smaller
+    /// area, the stove would turn on and off frequently which is
+    /// not desired (e.g., higher gas consumption).
+    pub stove_activation_area: f32,
+    /// The duration in minutes to wait after a valve state change
+    /// before considering it for stove activation. This ensures
+    /// the valve has had enough time to open for heating.
+    pub stove_activation_delay_minutes: u32,
+    /// The duration in minutes to look back for temperature readings
+    /// when calculating average value for valve control.
+    pub temperature_lookback_minutes: u32,
+    /// The minimum number of temperature readings required for valve
+    /// control.
+    pub min_temperature_readings: usize,
+    /// The heating pipe temperature at which the stove is turned off
+    /// to protect the floor screed from overheating.
+    pub max_supply_temperature: f32,
+    /// How far the heating pipe has to cool down below the ceiling
+    /// before the stove can be turned on again.
+    pub supply_temperature_recovery: f32,
+    /// How long the stove can be on before the pipe is expected to warm up.
+    pub pipe_warmup_minutes: u32,
+    /// The minimum rise of the pipe temperature after the warm-up time.
+    /// A smaller rise is recorded as a fault.
+    pub min_pipe_temperature_rise: f32,
+}
+
+impl Default for ControllerConfiguration {
+    fn default() -> Self {
+        ControllerConfiguration {
+            hysteresis: 0.0,
+            min_valve_on_minutes: 0,
+            min_valve_off_minutes: 0,
+            stove_activation_area: 16.0,
+            stove_activation_delay_minutes: 2,
+            temperature_lookback_minutes: 10,
+            min_temperature_readings: 3,
+            max_supply_temperature: 55.0,
+            supply_temperature_recovery: 5.0,
+            pipe_warmup_minutes: 30,
+            min_pipe_temperature_rise: 2.0,
+        }
+    }
+}
+
+/// Cron expressions (with seconds) of the scheduler jobs.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfiguration {
+    /// How often to read temperatures from the sensors.
+    pub temperature_cron: String,
+    /// How often to read the relay states. They are also stored
+    /// when updating their state.
+    pub relay_cron: String,
+    /// How often to update the relay states for valves based on,
+    /// e.g., the room temperatures.
+    pub valve_controller_cron: String,
+    /// How often to update the stove state based on the open valve areas.
+    pub stove_controller_cron: String,
+    /// How often to downsample and prune old readings.
+    pub retention_cron: String,
+}
+
+impl Default for SchedulerConfiguration {
+    fn default() -> Self {
+        SchedulerConfiguration {
+            temperature_cron: "0 */2 * * * *".to_string(),
+            relay_cron: "45 */15 * * * *".to_string(),
+            valve_controller_cron: "30 */2 * * * *".to_string(),
+            stove_controller_cron: "0 */5 * * * *".to_string(),
+            retention_cron: "0 15 3 * * *".to_string(),
+        }
+    }
+}
+
+impl ControllerConfiguration {
+    pub fn hysteresis(&self, room: &Room) -> f32 {
+        room.hysteresis.unwrap_or(self.hysteresis)
+    }
+
+    /// The minimum time the valve of the room has to stay in the given state.
+    pub fn min_valve_duration(&self, room: &Room, state: bool) -> Duration {
+        let minutes = if state {
+            room.min_valve_on_minutes
+                .unwrap_or(self.min_valve_on_minutes)
+        } else {
+            room.min_valve_off_minutes
+                .unwrap_or(self.min_valve_off_minutes)
+        };
+        Duration::minutes(minutes as i64)
+    }
 }
 
 impl HeatingConfiguration {
     /// Reads the heating configuration from a JSON file.
     pub fn from_file(path: &str) -> Result<Self, NeuroheatError> {
+        let mut config = Self::read_file(path)?;
+        config.attach_devices(&HardwareDevices);
+
+        Ok(config)
+    }
+
+    /// Reads the heating configuration from a JSON file without attaching
+    /// any devices.
+    pub fn read_file(path: &str) -> Result<Self, NeuroheatError> {
         let file = File::open(path).map_err(|e| {
             let err_msg = format!("Failed to open configuration file {}: {}", path, e);
             log::error!("{}", err_msg);
             NeuroheatError::ConfigurationError(err_msg)
         })?;
-        let reader = BufReader::new(file);
-        let mut config: HeatingConfiguration = serde_json::from_reader(reader).map_err(|e| {
-            let err_msg = format!("Failed to parse configuration file {}: {}", path, e);
+
+        Self::from_reader(BufReader::new(file), path)
+    }
+
+    /// Reads and validates a configuration without attaching any devices.
+    /// The name identifies the configuration in error messages.
+    pub fn from_reader(reader: impl Read, name: &str) -> Result<Self, NeuroheatError> {
+        let config: HeatingConfiguration = serde_json::from_reader(reader).map_err(|e| {
+            let err_msg = format!("Failed to parse configuration file {}: {}", name, e);
             log::error!("{}", err_msg);
             NeuroheatError::ConfigurationError(err_msg)
         })?;
 
-        for room in &mut config.rooms {
-            room.sensor = Some(Arc::new(DS18B20::new(room.sensor_id.clone())));
-            room.valve_reader = Some(Arc::new(GPIOController::new(room.valve_pin)));
-        }
-        config.pipe_sensor = Some(Arc::new(DS18B20::new(config.pipe_sensor_id.clone())));
-        config.stove_reader = Some(Arc::new(GPIOController::new(config.stove_pin)));
+        config.validate().map_err(|e| {
+            let err_msg = format!("Invalid configuration file {}: {}", name, e);
+            log::error!("{}", err_msg);
+            NeuroheatError::ConfigurationError(err_msg)
+        })?;
 
         Ok(config)
     }
+
+    /// Creates the sensors and relays of the rooms, the pipe, the outdoor
+    /// sensor and the stove.
+    pub fn attach_devices(&mut self, devices: &dyn Devices) {
+        for room in &mut self.rooms {
+            room.sensor = Some(devices.temperature_sensor(&room.sensor_id));
+            room.valve_reader = Some(devices.relay(room.valve_pin, &room.valve_relay));
+        }
+        self.pipe_sensor = Some(devices.temperature_sensor(&self.pipe_sensor_id));
+        self.outdoor_sensor = self
+            .outdoor_sensor_id
+            .as_ref()
+            .map(|id| devices.temperature_sensor(id));
+        self.stove_reader = Some(devices.relay(self.stove_pin, &self.stove_relay));
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let hysteresis_values = std::iter::once(("controller", self.controller.hysteresis)).chain(
+            self.rooms
+                .iter()
+                .filter_map(|room| room.hysteresis.map(|h| (room.key.as_str(), h))),
+        );
+        for (key, hysteresis) in hysteresis_values {
+            if !(0.0..=MAX_HYSTERESIS).contains(&hysteresis) {
+                return Err(format!(
+                    "hysteresis for {} must be between 0.0 and {:.1}°C",
+                    key, MAX_HYSTERESIS
+                ));
+            }
+        }
+
+        self.pipe_sensor_range.validate("pipe_sensor_range")?;
+        self.outdoor_sensor_range.validate("outdoor_sensor_range")?;
+        for room in &self.rooms {
+            room.sensor_range
+                .validate(&format!("{}.sensor_range", room.key))?;
+
+            if let Some(compensation) = &room.weather_compensation {
+                if compensation.slope < 0.0 {
+                    return Err(format!(
+                        "{}.weather_compensation.slope must not be negative",
+                        room.key
+                    ));
+                }
+                if compensation.max_adjustment < 0.0 {
+                    return Err(format!(
+                        "{}.weather_compensation.max_adjustment must not be negative",
+                        room.key
+                    ));
+                }
+                if self.outdoor_sensor_id.is_none() {
+                    return Err(format!(
+                        "{}.weather_compensation requires outdoor_sensor_id",
+                        room.key
+                    ));
+                }
+            }
+
+            if let ValveStrategyConfiguration::Pi(pi) = &room.valve_strategy {
+                if pi.cycle_minutes == 0 {
+                    return Err(format!(
+                        "{}.valve_strategy.cycle_minutes must be positive",
+                        room.key
+                    ));
+                }
+                let min_cycle = self.controller.min_valve_duration(room, true)
+                    + self.controller.min_valve_duration(room, false);
+                if Duration::minutes(pi.cycle_minutes as i64) < min_cycle {
+                    return Err(format!(
+                        "{}.valve_strategy.cycle_minutes must be at least the minimum valve on and off times ({} min)",
+                        room.key,
+                        min_cycle.num_minutes()
+                    ));
+                }
+                if pi.proportional_gain < 0.0 || pi.integral_gain < 0.0 {
+                    return Err(format!(
+                        "{}.valve_strategy gains must not be negative",
+                        room.key
+                    ));
+                }
+            }
+        }
+
+        let controller = &self.controller;
+        if controller.stove_activation_area < 0.0 {
+            return Err("controller.stove_activation_area must not be negative".to_string());
+        }
+        if controller.temperature_lookback_minutes == 0 {
+            return Err("controller.temperature_lookback_minutes must be positive".to_string());
+        }
+        if controller.min_temperature_readings == 0 {
+            return Err("controller.min_temperature_readings must be positive".to_string());
+        }
+        if controller.supply_temperature_recovery < 0.0 {
+            return Err("controller.supply_temperature_recovery must not be negative".to_string());
+        }
+        if controller.pipe_warmup_minutes == 0 {
+            return Err("controller.pipe_warmup_minutes must be positive".to_string());
+        }
+
+        if self.health.max_reading_age_minutes == 0 {
+            return Err("health.max_reading_age_minutes must be positive".to_string());
+        }
+        if self.health.max_missed_job_runs == 0 {
+            return Err("health.max_missed_job_runs must be positive".to_string());
+        }
+        if self.health.max_job_run_seconds == 0 {
+            return Err("health.max_job_run_seconds must be positive".to_string());
+        }
+
+        if self.retention.raw_days == 0 {
+            return Err("retention.raw_days must be positive".to_string());
+        }
+        if self.retention.hourly_days < self.retention.raw_days {
+            return Err("retention.hourly_days must not be lower than raw_days".to_string());
+        }
+
+        let scheduler = &self.scheduler;
+        for (name, expression) in [
+            ("temperature_cron", &scheduler.temperature_cron),
+            ("relay_cron", &scheduler.relay_cron),
+            ("valve_controller_cron", &scheduler.valve_controller_cron),
+            ("stove_controller_cron", &scheduler.stove_controller_cron),
+            ("retention_cron", &scheduler.retention_cron),
+        ] {
+            scheduler::parse_cron(expression)
+                .map_err(|e| format!("invalid scheduler.{} {:?}: {}", name, expression, e))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Room {
-    pub fn get_expected_temperature(&self) -> Option<f32> {
-        let current_hour = Local::now().hour() as u8;
+    /// Returns the expected temperature at the given time. An active
+    /// override takes precedence over the temperature schedule; otherwise
+    /// the scheduled temperature is adjusted by the weather compensation
+    /// (if configured and the outdoor temperature is known).
+    pub fn get_expected_temperature(
+        &self,
+        temperature_override: Option<&TemperatureOverride>,
+        outdoor_temperature: Option<f32>,
+        now: DateTime<Utc>,
+    ) -> Option<f32> {
+        match temperature_override {
+            Some(temperature_override) => Some(temperature_override.temperature),
+            None => self
+                .get_expected_temperature_at(&now.with_timezone(&Local).naive_local())
+                .map(|temperature| {
+                    temperature + self.weather_adjustment(outdoor_temperature).unwrap_or(0.0)
+                }),
+        }
+    }
 
+    /// Returns the weather-compensation adjustment of the scheduled temperature.
+    pub fn weather_adjustment(&self, outdoor_temperature: Option<f32>) -> Option<f32> {
+        let compensation = self.weather_compensation.as_ref()?;
+        outdoor_temperature.map(|outdoor| compensation.adjustment(outdoor))
+    }
+
+    /// Returns the scheduled temperature for the given local time. When
+    /// several entries cover the same time, the first one listed wins.
+    pub fn get_expected_temperature_at(&self, datetime: &NaiveDateTime) -> Option<f32> {
+        self.active_schedule_index(datetime)
+            .map(|index| self.temperature_schedule[index].temperature)
+    }
+
+    /// Returns the local time at which the scheduled temperature next
+    /// changes, looking up to a week ahead. Adjacent entries with the same
+    /// temperature (e.g., a night split at midnight) count as one.
+    pub fn next_schedule_change(&self, datetime: &NaiveDateTime) -> Option<NaiveDateTime> {
+        let current = self.get_expected_temperature_at(datetime);
+        let midnight = datetime.date().and_hms_opt(0, 0, 0)?;
+
+        let mut boundaries: Vec<NaiveDateTime> = (0..=7)
+            .flat_map(|day| {
+                self.temperature_schedule.iter().flat_map(move |schedule| {
+                    [schedule.start_minute, schedule.end_minute].map(|minute| {
+                        midnight + Duration::days(day) + Duration::minutes(minute as i64)
+                    })
+                })
+            })
+            .filter(|boundary| boundary > datetime)
+            .collect();
+        boundaries.sort();
+        boundaries.dedup();
+
+        boundaries
+            .into_iter()
+            .find(|boundary| self.get_expected_temperature_at(boundary) != current)
+    }
+
+    fn active_schedule_index(&self, datetime: &NaiveDateTime) -> Option<usize> {
         self.temperature_schedule
             .iter()
-            .find(|schedule| {
-                current_hour >= schedule.start_hour && current_hour < schedule.end_hour
+            .position(|schedule| schedule.covers(datetime))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        config, config_json_with, config_json_with_schedule, config_with, parse_config, start,
+        with_settings, CONFIG,
+    };
+
+    fn schedule(json: &str) -> Result<TemperatureSchedule, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    /// 2024-01-15 is a Monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("2024-01-{} {}", day, time), "%Y-%m-%d %H:%M")
+            .unwrap()
+    }
+
+    #[test]
+    fn converts_hours_and_times_to_minutes() {
+        let hours =
+            schedule(r#"{ "start_hour": 6, "end_hour": 24, "temperature": 21.0 }"#).unwrap();
+        assert_eq!((hours.start_minute, hours.end_minute), (360, 1440));
+        assert!(hours.days.is_empty());
+
+        let times = schedule(
+            r#"{ "start": "06:30", "end": "22:15", "days": ["workdays", "Mon", "saturday"], "temperature": 21.0 }"#,
+        )
+        .unwrap();
+        assert_eq!((times.start_minute, times.end_minute), (390, 1335));
+        assert_eq!(
+            times.days,
+            [
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for json in [
+            r#"{ "start_hour": 6, "start": "06:00", "end_hour": 8, "temperature": 21.0 }"#,
+            r#"{ "end_hour": 8, "temperature": 21.0 }"#,
+            r#"{ "start_hour": 25, "end_hour": 8, "temperature": 21.0 }"#,
+            r#"{ "start": "6", "end": "08:00", "temperature": 21.0 }"#,
+            r#"{ "start": "06:60", "end": "08:00", "temperature": 21.0 }"#,
+            r#"{ "start": "22:00", "end": "24:01", "temperature": 21.0 }"#,
+            r#"{ "start": "08:00", "end": "08:00", "temperature": 21.0 }"#,
+            r#"{ "start_hour": 22, "end_hour": 6, "temperature": 21.0 }"#,
+            r#"{ "start_hour": 6, "end_hour": 8, "days": ["someday"], "temperature": 21.0 }"#,
+        ] {
+            assert!(schedule(json).is_err(), "accepted {}", json);
+        }
+    }
+
+    #[test]
+    fn covers_the_minutes_from_start_to_end() {
+        let morning =
+            schedule(r#"{ "start": "06:30", "end": "08:15", "temperature": 21.0 }"#).unwrap();
+
+        assert!(!morning.covers(&at(15, "06:29")));
+        assert!(morning.covers(&at(15, "06:30")));
+        assert!(morning.covers(&at(15, "08:14")));
+        assert!(!morning.covers(&at(15, "08:15")));
+    }
+
+    #[test]
+    fn covers_the_end_of_the_day_up_to_midnight() {
+        let evening =
+            schedule(r#"{ "start": "22:00", "end": "24:00", "temperature": 19.0 }"#).unwrap();
+        let night =
+            schedule(r#"{ "start": "00:00", "end": "06:00", "temperature": 19.0 }"#).unwrap();
+
+        assert!(evening.covers(&at(15, "23:59")));
+        assert!(!evening.covers(&at(16, "00:00")));
+        assert!(night.covers(&at(16, "00:00")));
+        assert!(!night.covers(&at(15, "23:59")));
+    }
+
+    #[test]
+    fn covers_only_the_listed_days() {
+        let weekend = schedule(
+            r#"{ "start_hour": 8, "end_hour": 22, "days": ["weekend"], "temperature": 21.0 }"#,
+        )
+        .unwrap();
+
+        assert!(!weekend.covers(&at(19, "12:00")));
+        assert!(weekend.covers(&at(20, "12:00")));
+        assert!(weekend.covers(&at(21, "12:00")));
+        assert!(!weekend.covers(&at(22, "12:00")));
+    }
+
+    const COMPENSATION: &str = r#""weather_compensation": { "reference_temperature": 10.0, "slope": 0.1, "max_adjustment": 1.0 }"#;
+
+    fn compensated_config() -> HeatingConfiguration {
+        let json = with_settings(
+            &config_json_with(COMPENSATION),
+            r#""outdoor_sensor_id": "outdoor-sensor""#,
+        );
+        parse_config(&json).unwrap()
+    }
+
+    #[test]
+    fn compensates_the_schedule_for_the_outdoor_temperature() {
+        let config = compensated_config();
+        let office = &config.rooms[0];
+
+        assert_eq!(
+            office.get_expected_temperature(None, None, start()),
+            Some(21.0)
+        );
+        assert_eq!(
+            office.get_expected_temperature(None, Some(5.0), start()),
+            Some(21.5)
+        );
+        assert_eq!(
+            office.get_expected_temperature(None, Some(-20.0), start()),
+            Some(22.0)
+        );
+        assert_eq!(
+            office.get_expected_temperature(None, Some(15.0), start()),
+            Some(20.5)
+        );
+    }
+
+    #[test]
+    fn does_not_compensate_overrides() {
+        let config = compensated_config();
+        let temperature_override = TemperatureOverride {
+            key: "office".to_string(),
+            temperature: 18.0,
+            created_at: start(),
+            expires_at: start() + Duration::hours(1),
+        };
+
+        assert_eq!(
+            config.rooms[0].get_expected_temperature(
+                Some(&temperature_override),
+                Some(-20.0),
+                start()
+            ),
+            Some(18.0)
+        );
+    }
+
+    #[test]
+    fn rejects_compensation_without_an_outdoor_sensor() {
+        let error = parse_config(&config_json_with(COMPENSATION))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("office.weather_compensation requires outdoor_sensor_id"));
+    }
+
+    #[test]
+    fn rejects_an_invalid_cron_expression() {
+        let json = with_settings(CONFIG, r#""scheduler": { "relay_cron": "every minute" }"#);
+
+        let error = parse_config(&json).unwrap_err().to_string();
+
+        assert!(error.contains("invalid scheduler.relay_cron"));
+    }
+
+    #[test]
+    fn selects_the_valve_strategy_per_room() {
+        let config = config_with(r#""valve_strategy": { "type": "pi", "integral_gain": 0.05 }"#);
+
+        assert_eq!(
+            config.rooms[0].valve_strategy,
+            ValveStrategyConfiguration::Pi(PiConfiguration {
+                integral_gain: 0.05,
+                ..PiConfiguration::default()
             })
-            .map(|schedule| schedule.temperature)
+        );
+        assert_eq!(
+            config.rooms[1].valve_strategy,
+            ValveStrategyConfiguration::Threshold
+        );
+
+        let json = config_json_with(r#""valve_strategy": { "type": "pi", "cycle_minutes": 0 }"#);
+        let error = parse_config(&json).unwrap_err().to_string();
+
+        assert!(error.contains("office.valve_strategy.cycle_minutes must be positive"));
+    }
+
+    #[test]
+    fn rejects_a_pi_cycle_shorter_than_the_minimum_valve_times() {
+        // The test configuration keeps valves open and closed for at least
+        // 10 minutes each.
+        let json = config_json_with(r#""valve_strategy": { "type": "pi", "cycle_minutes": 15 }"#);
+
+        let error = parse_config(&json).unwrap_err().to_string();
+
+        assert!(error.contains(
+            "office.valve_strategy.cycle_minutes must be at least the minimum valve on and off times (20 min)"
+        ));
+
+        let json = config_json_with(
+            r#""min_valve_on_minutes": 5, "min_valve_off_minutes": 5,
+      "valve_strategy": { "type": "pi", "cycle_minutes": 15 }"#,
+        );
+        assert!(parse_config(&json).is_ok());
+    }
+    #[test]
+    fn sensor_range_rejects_implausible_readings() {
+        let range = SensorRange {
+            min: 0.0,
+            max: 50.0,
+            max_change_per_minute: Some(0.5),
+        };
+        let now = start();
+        let minutes_ago = |minutes| Some((20.0, now - chrono::Duration::minutes(minutes)));
+
+        assert!(range.check(-0.5, None, now).is_err());
+        assert!(range.check(50.5, None, now).is_err());
+        assert!(range.check(f32::NAN, None, now).is_err());
+        // Without a previous reading, only the range applies.
+        assert_eq!(range.check(45.0, None, now), Ok(()));
+
+        assert_eq!(range.check(21.0, minutes_ago(2), now), Ok(()));
+        let reason = range.check(22.0, minutes_ago(2), now).unwrap_err();
+        assert!(reason.starts_with("changed by 1.00°C/min"), "{}", reason);
+        assert_eq!(range.check(25.0, minutes_ago(10), now), Ok(()));
+        // Readings taken shortly one after another count as a minute apart.
+        assert_eq!(range.check(20.5, minutes_ago(0), now), Ok(()));
+        assert!(range.check(20.6, minutes_ago(0), now).is_err());
+
+        // Only the range applies without a limit on the change.
+        assert_eq!(SensorRange::room().check(45.0, minutes_ago(1), now), Ok(()));
+    }
+    fn office_with_schedule(schedule: &str) -> Room {
+        parse_config(&config_json_with_schedule(schedule))
+            .unwrap()
+            .rooms
+            .remove(0)
+    }
+
+    #[test]
+    fn finds_the_next_schedule_change_across_midnight() {
+        let office = office_with_schedule(
+            r#"[
+              { "start": "00:00", "end": "06:00", "temperature": 18.0 },
+              { "start": "06:00", "end": "22:00", "temperature": 21.0 },
+              { "start": "22:00", "end": "24:00", "temperature": 18.0 }
+            ]"#,
+        );
+
+        assert_eq!(
+            office.next_schedule_change(&at(15, "05:59")),
+            Some(at(15, "06:00"))
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(15, "06:00")),
+            Some(at(15, "22:00"))
+        );
+        // The night is split at midnight but keeps its temperature.
+        assert_eq!(
+            office.next_schedule_change(&at(15, "23:00")),
+            Some(at(16, "06:00"))
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(16, "00:00")),
+            Some(at(16, "06:00"))
+        );
+    }
+
+    #[test]
+    fn finds_the_next_schedule_change_on_other_days() {
+        let office = office_with_schedule(
+            r#"[
+              { "start": "06:00", "end": "22:00", "days": ["workdays"], "temperature": 21.0 },
+              { "start": "08:00", "end": "23:00", "days": ["weekend"], "temperature": 20.0 }
+            ]"#,
+        );
+
+        // 2024-01-19 is a Friday.
+        assert_eq!(
+            office.next_schedule_change(&at(19, "12:00")),
+            Some(at(19, "22:00"))
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(19, "22:30")),
+            Some(at(20, "08:00"))
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(20, "12:00")),
+            Some(at(20, "23:00"))
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(21, "23:30")),
+            Some(at(22, "06:00"))
+        );
+    }
+
+    #[test]
+    fn has_no_schedule_change_for_a_constant_schedule() {
+        let office = config().rooms.remove(0);
+        assert_eq!(office.next_schedule_change(&at(15, "12:00")), None);
+
+        // A single weekly entry changes again a week later at the latest.
+        let office = office_with_schedule(
+            r#"[{ "start": "06:00", "end": "08:00", "days": ["Mon"], "temperature": 21.0 }]"#,
+        );
+        assert_eq!(
+            office.next_schedule_change(&at(15, "08:00")),
+            Some(at(22, "06:00"))
+        );
     }
 }
@@ -0,0 +1,232 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::NeuroheatError;
+
+/// The time range covered by a history query when `from` is not given.
+const DEFAULT_HISTORY_HOURS: i64 = 24;
+
+/// The longest time range that can be requested at once.
+const MAX_HISTORY_DAYS: i64 = 366;
+
+/// The number of buckets to aim for when the resolution is not given.
+const DEFAULT_BUCKET_COUNT: i64 = 300;
+
+/// The maximum number of buckets a single query can return.
+const MAX_BUCKET_COUNT: i64 = 5000;
+
+/// The query string of a history request as sent by the client.
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub resolution: Option<String>,
+}
+
+/// A validated history query.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub resolution: Duration,
+}
+
+/// Aggregated temperature readings within a single bucket.
+#[derive(Debug, Serialize)]
+pub struct TemperatureBucket {
+    /// The start of the bucket.
+    pub timestamp: DateTime<Utc>,
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+    /// The average expected temperature (if the key has one).
+    pub expected_temperature: Option<f32>,
+    /// The number of readings aggregated in the bucket.
+    pub samples: u32,
+}
+
+/// Aggregated relay states within a single bucket.
+#[derive(Debug, Serialize)]
+pub struct StateBucket {
+    /// The start of the bucket.
+    pub timestamp: DateTime<Utc>,
+    /// The most recent state known in the bucket.
+    pub heating_enabled: bool,
+    /// The fraction of the bucket the relay was on.
+    pub enabled_ratio: f32,
+    /// The number of states recorded in the bucket.
+    pub samples: u32,
+}
+
+/// How long a recorded relay state is assumed to hold without a newer
+/// record (they are recorded every 15 minutes). Longer gaps, such as
+/// downtime, do not count as on-time.
+pub const MAX_STATE_SECONDS: i64 = 3600;
+
+/// A period with a known share of on-time: a raw state lasting until the
+/// next one, or a downsampled aggregate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatePeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// The fraction of the period the relay was on.
+    pub enabled_ratio: f64,
+    /// The state at the end of the period.
+    pub last_state: bool,
+    /// The number of recorded states the period stands for.
+    pub samples: u32,
+}
+
+/// The history of a single key (a room, the pipe, or the stove).
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub key: String,
+    pub label: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub resolution_seconds: i64,
+    pub temperatures: Vec<TemperatureBucket>,
+    pub states: Vec<StateBucket>,
+}
+
+impl HistoryQuery {
+    /// Validates the query parameters, filling in defaults relative to `now`.
+    pub fn from_params(params: &HistoryParams, now: DateTime<Utc>) -> Result<Self, NeuroheatError> {
+        let to = match &params.to {
+            Some(to) => parse_datetime(to)?,
+            None => now,
+        };
+        let from = match &params.from {
+            Some(from) => parse_datetime(from)?,
+            None => to - Duration::hours(DEFAULT_HISTORY_HOURS),
+        };
+
+        if from >= to {
+            return Err(invalid_query("from must be before to"));
+        }
+        if to - from > Duration::days(MAX_HISTORY_DAYS) {
+            return Err(invalid_query(&format!(
+                "time range must not exceed {} days",
+                MAX_HISTORY_DAYS
+            )));
+        }
+
+        let span_seconds = (to - from).num_seconds();
+        let resolution = match &params.resolution {
+            Some(resolution) => parse_resolution(resolution)?,
+            None => {
+                // round up to whole minutes to keep bucket boundaries readable
+                let seconds = (span_seconds + DEFAULT_BUCKET_COUNT - 1) / DEFAULT_BUCKET_COUNT;
+                Duration::minutes((seconds + 59) / 60)
+            }
+        };
+
+        if span_seconds / resolution.num_seconds() > MAX_BUCKET_COUNT {
+            return Err(invalid_query(&format!(
+                "resolution is too fine for the time range (more than {} buckets)",
+                MAX_BUCKET_COUNT
+            )));
+        }
+
+        Ok(HistoryQuery {
+            from,
+            to,
+            resolution,
+        })
+    }
+}
+
+/// Splits the periods (ordered and not overlapping) into the buckets of
+/// the query. Every bucket in the range is returned; its ratio is weighted
+/// by the time each period overlaps it, so time without a known state
+/// counts as off.
+pub fn state_buckets(periods: &[StatePeriod], query: &HistoryQuery) -> Vec<StateBucket> {
+    let resolution = query.resolution.num_seconds();
+    let mut bucket_start = query.from.timestamp().div_euclid(resolution) * resolution;
+    let to = query.to.timestamp();
+    let mut first = 0;
+    let mut buckets = Vec::new();
+
+    while bucket_start < to {
+        let bucket_end = bucket_start + resolution;
+        // Parts of the edge buckets outside the range do not count.
+        let covered_start = bucket_start.max(query.from.timestamp());
+        let covered_end = bucket_end.min(to);
+
+        // Skip the periods that ended before the bucket (but not the ones
+        // recorded right at its start).
+        while first < periods.len()
+            && periods[first].end.timestamp() <= covered_start
+            && periods[first].start.timestamp() < covered_start
+        {
+            first += 1;
+        }
+
+        let mut enabled_seconds = 0.0;
+        let mut heating_enabled = false;
+        let mut samples = 0;
+        for period in periods[first..]
+            .iter()
+            .take_while(|period| period.start.timestamp() < covered_end)
+        {
+            let start = period.start.timestamp();
+            let overlap = period.end.timestamp().min(covered_end) - start.max(covered_start);
+            if overlap > 0 {
+                enabled_seconds += overlap as f64 * period.enabled_ratio;
+            }
+            if start >= covered_start {
+                samples += period.samples;
+            }
+            if overlap > 0 || start >= covered_start {
+                heating_enabled = period.last_state;
+            }
+        }
+
+        buckets.push(StateBucket {
+            timestamp: DateTime::from_timestamp(bucket_start, 0).unwrap_or(query.from),
+            heating_enabled,
+            enabled_ratio: (enabled_seconds / (covered_end - covered_start) as f64) as f32,
+            samples,
+        });
+        bucket_start = bucket_end;
+    }
+
+    buckets
+}
+
+/// Accepts RFC 3339 timestamps as well as the `YYYY-MM-DD HH:MM:SS`
+/// format (in UTC) used by the other endpoints.
+fn parse_datetime(value: &str) -> Result<DateTime<Utc>, NeuroheatError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|dt| dt.and_utc())
+        })
+        .map_err(|_| invalid_query(&format!("invalid timestamp {:?}", value)))
+}
+
+/// Parses resolutions such as `90s`, `15m`, `1h` or `1d`.
+fn parse_resolution(value: &str) -> Result<Duration, NeuroheatError> {
+    let invalid = || invalid_query(&format!("invalid resolution {:?}", value));
+    let split_at = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (amount, unit) = value.split_at(split_at);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+
+    if amount == 0 {
+        return Err(invalid());
+    }
+
+    match unit {
+        "s" => Ok(Duration::seconds(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+fn invalid_query(msg: &str) -> NeuroheatError {
+    NeuroheatError::ValidationError(format!("Invalid history query: {}", msg))
+}
@@ -1,43 +1,181 @@
 mod api;
 mod cli;
+mod clock;
 mod controller;
+mod dashboard;
 mod db;
+mod devices;
 mod error;
+mod events;
+mod health;
 mod heating_configuration;
+mod history;
+mod metrics;
+mod migrations;
 mod relay;
+mod replay;
 mod repo;
+mod report;
+mod retention;
 mod scheduler;
+mod simulator;
+mod state;
+mod systemd;
+mod temperature_override;
 mod temperature_sensor;
+#[cfg(test)]
+mod testing;
+mod valve_strategy;
 
 use heating_configuration::HeatingConfiguration;
 
+use chrono::{DurationRound, Utc};
 use clap::Parser;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let args = cli::Args::parse();
+    let mut args = cli::Args::parse();
     let log_level = cli::parse_log_level(&args);
 
     env_logger::Builder::new().filter(None, log_level).init();
 
+    if let Some(command) = args.command.take() {
+        return run_command(command, &args).await;
+    }
+
     let config_path = &args.heating_config_path;
     let config = Arc::new(HeatingConfiguration::from_file(config_path)?);
     let conn = db::open(args.database_path);
     let shared_conn = Arc::new(Mutex::new(conn));
+    let metrics = Arc::new(metrics::Metrics::new(Arc::clone(&config.clock)));
+    let events = Arc::new(events::Events::new());
 
     // initialize database if necessary
     db::init(&shared_conn, &config)?;
 
-    // setup GPIO pins
+    // setup GPIO pins and put relays into a known state
     relay::setup_all_relays(&config)?;
+    relay::apply_safe_state(&config, &shared_conn, &metrics, &events)?;
 
     // start scheduler (e.g., reading data from sensors)
-    scheduler::start_scheduler(Arc::clone(&config), Arc::clone(&shared_conn)).await?;
+    let mut scheduler = scheduler::start_scheduler(
+        Arc::clone(&config),
+        Arc::clone(&shared_conn),
+        Arc::clone(&metrics),
+        Arc::clone(&events),
+    )
+    .await?;
+
+    // let systemd know the service is up and keep its watchdog fed
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog(Arc::clone(&config), Arc::clone(&metrics));
 
-    // start API server
-    api::start_server(Arc::clone(&shared_conn), args.api_port).await;
+    // start API server and run until a termination signal is received
+    tokio::select! {
+        _ = api::start_server(
+            Arc::clone(&config),
+            Arc::clone(&shared_conn),
+            Arc::clone(&metrics),
+            Arc::clone(&events),
+            args.api_port,
+        ) => {}
+        signal = shutdown_signal() => log::info!("Received {}, shutting down", signal),
+    }
+
+    // no job may switch a relay once the safe state is applied
+    systemd::notify("STOPPING=1");
+    if let Err(e) = scheduler.shutdown().await {
+        log::error!("Failed to stop the scheduler: {}", e);
+    }
+    relay::apply_safe_state(&config, &shared_conn, &metrics, &events)?;
 
     Ok(())
 }
+
+/// Waits for SIGTERM or SIGINT. If the handlers cannot be installed, it
+/// never returns and the service runs until the API server stops.
+async fn shutdown_signal() -> &'static str {
+    let signals = signal(SignalKind::terminate())
+        .and_then(|terminate| Ok((terminate, signal(SignalKind::interrupt())?)));
+    let (mut terminate, mut interrupt) = match signals {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::error!("Failed to listen for termination signals: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
+
+async fn run_command(
+    command: cli::Command,
+    args: &cli::Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        cli::Command::Db {
+            command: cli::DbCommand::Migrate { dry_run },
+        } => {
+            if dry_run {
+                // read-only, so that not even a missing database is created
+                let conn = db::open_read_only(&args.database_path)?;
+                print!("{}", migrations::dry_run(&conn)?);
+            } else {
+                let conn = db::open(args.database_path.clone());
+                let applied = migrations::migrate(&conn)?;
+                println!(
+                    "Applied {} migration(s), schema version: {}",
+                    applied,
+                    migrations::current_version(&conn)?
+                );
+            }
+
+            Ok(())
+        }
+        cli::Command::Simulate {
+            house_path,
+            hours,
+            start,
+            output_database_path,
+        } => {
+            let config = HeatingConfiguration::read_file(&args.heating_config_path)?;
+            let house = simulator::HouseConfiguration::from_file(&house_path, &config)?;
+            let start = match start {
+                Some(start) => start,
+                None => Utc::now().duration_trunc(chrono::Duration::hours(1))?,
+            };
+            let conn = match output_database_path {
+                Some(path) => db::open_empty(&path)?,
+                None => db::open(":memory:".to_string()),
+            };
+
+            let report = simulator::simulate(
+                config,
+                &house,
+                Arc::new(Mutex::new(conn)),
+                start,
+                chrono::Duration::hours(hours as i64),
+            )
+            .await?;
+            println!("{}", report);
+
+            Ok(())
+        }
+        cli::Command::Replay { from, to, config } => {
+            let config = HeatingConfiguration::read_file(&config)?;
+            let recorded = Arc::new(Mutex::new(db::open_read_only(&args.database_path)?));
+            let conn = Arc::new(Mutex::new(db::open(":memory:".to_string())));
+
+            let comparison = replay::replay(config, &recorded, conn, from, to).await?;
+            println!("{}", comparison);
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+/// Statistics of a single scheduler job.
+#[derive(Debug, Default, Clone)]
+pub struct JobStats {
+    pub runs: u64,
+    pub failures: u64,
+    pub last_duration: Duration,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// When the run in progress started.
+    pub running_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsData {
+    room_temperatures: BTreeMap<String, f32>,
+    expected_temperatures: BTreeMap<String, f32>,
+    pipe_temperature: Option<f32>,
+    outdoor_temperature: Option<f32>,
+    valve_states: BTreeMap<String, bool>,
+    stove_state: Option<bool>,
+    sensor_errors: BTreeMap<String, u64>,
+    rejected_temperatures: BTreeMap<String, u64>,
+    relay_writes: BTreeMap<(String, &'static str), u64>,
+    stove_faults: BTreeMap<&'static str, u64>,
+    active_stove_faults: BTreeMap<&'static str, bool>,
+    jobs: BTreeMap<&'static str, JobStats>,
+}
+
+/// In-memory metrics exposed in the Prometheus text format. Times are read
+/// from the clock of the configuration.
+#[derive(Debug)]
+pub struct Metrics {
+    clock: Arc<dyn Clock>,
+    started_at: DateTime<Utc>,
+    data: Mutex<MetricsData>,
+}
+
+impl Metrics {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Metrics {
+            started_at: clock.now(),
+            clock,
+            data: Mutex::default(),
+        }
+    }
+
+    /// When the application (i.e., metrics collection) started.
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    /// Returns the statistics of the scheduler jobs that have run so far.
+    pub fn job_stats(&self) -> BTreeMap<&'static str, JobStats> {
+        match self.data.lock() {
+            Ok(data) => data.jobs.clone(),
+            Err(poisoned) => poisoned.into_inner().jobs.clone(),
+        }
+    }
+
+    pub fn set_room_temperature(&self, key: &str, temperature: f32, expected: Option<f32>) {
+        self.update(|data| {
+            data.room_temperatures.insert(key.to_string(), temperature);
+            match expected {
+                Some(expected) => data.expected_temperatures.insert(key.to_string(), expected),
+                None => data.expected_temperatures.remove(key),
+            };
+        });
+    }
+
+    pub fn set_pipe_temperature(&self, temperature: f32) {
+        self.update(|data| data.pipe_temperature = Some(temperature));
+    }
+
+    pub fn set_outdoor_temperature(&self, temperature: f32) {
+        self.update(|data| data.outdoor_temperature = Some(temperature));
+    }
+
+    /// Records the state of a relay identified by its key (`stove` or a room key).
+    pub fn set_relay_state(&self, key: &str, state: bool) {
+        self.update(|data| {
+            if key == "stove" {
+                data.stove_state = Some(state);
+            } else {
+                data.valve_states.insert(key.to_string(), state);
+            }
+        });
+    }
+
+    pub fn record_sensor_error(&self, key: &str) {
+        self.update(|data| *data.sensor_errors.entry(key.to_string()).or_default() += 1);
+    }
+
+    pub fn record_rejected_temperature(&self, key: &str) {
+        self.update(|data| {
+            *data
+                .rejected_temperatures
+                .entry(key.to_string())
+                .or_default() += 1
+        });
+    }
+
+    pub fn record_relay_write(&self, key: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.update(|data| {
+            *data
+                .relay_writes
+                .entry((key.to_string(), result))
+                .or_default() += 1
+        });
+    }
+
+    pub fn record_stove_fault(&self, fault: &'static str) {
+        self.update(|data| *data.stove_faults.entry(fault).or_default() += 1);
+    }
+
+    /// Marks a stove fault that persists until the controller clears it
+    /// (e.g., the pipe not warming up).
+    pub fn set_stove_fault_active(&self, fault: &'static str, active: bool) {
+        self.update(|data| {
+            data.active_stove_faults.insert(fault, active);
+        });
+    }
+
+    /// Returns the stove faults that have not been cleared.
+    pub fn active_stove_faults(&self) -> Vec<&'static str> {
+        let data = match self.data.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        data.active_stove_faults
+            .iter()
+            .filter(|(_, active)| **active)
+            .map(|(fault, _)| *fault)
+            .collect()
+    }
+
+    pub fn record_job_start(&self, job: &'static str) {
+        self.update(|data| {
+            data.jobs.entry(job).or_default().running_since = Some(self.clock.now())
+        });
+    }
+
+    pub fn record_job_run(&self, job: &'static str, duration: Duration, success: bool) {
+        self.update(|data| {
+            let stats = data.jobs.entry(job).or_default();
+            stats.running_since = None;
+            stats.runs += 1;
+            stats.last_duration = duration;
+            stats.last_run = Some(self.clock.now());
+            if success {
+                stats.last_success = stats.last_run;
+            } else {
+                stats.failures += 1;
+            }
+        });
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let data = match self.data.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut out = String::new();
+
+        write_family(
+            &mut out,
+            "neuroheat_room_temperature_celsius",
+            "gauge",
+            "The latest temperature reading of a room.",
+            data.room_temperatures
+                .iter()
+                .map(|(key, value)| (labels(&[("key", key)]), *value as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_room_expected_temperature_celsius",
+            "gauge",
+            "The expected temperature of a room at the latest reading.",
+            data.expected_temperatures
+                .iter()
+                .map(|(key, value)| (labels(&[("key", key)]), *value as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_pipe_temperature_celsius",
+            "gauge",
+            "The latest temperature reading of the heating pipe.",
+            data.pipe_temperature
+                .map(|value| (String::new(), value as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_outdoor_temperature_celsius",
+            "gauge",
+            "The latest outdoor temperature reading.",
+            data.outdoor_temperature
+                .map(|value| (String::new(), value as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_valve_state",
+            "gauge",
+            "Whether the valve of a room is open (1) or closed (0).",
+            data.valve_states
+                .iter()
+                .map(|(key, state)| (labels(&[("key", key)]), *state as u8 as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_stove_state",
+            "gauge",
+            "Whether the stove is on (1) or off (0).",
+            data.stove_state
+                .map(|state| (String::new(), state as u8 as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_sensor_read_errors_total",
+            "counter",
+            "The number of failed temperature sensor reads.",
+            data.sensor_errors
+                .iter()
+                .map(|(key, count)| (labels(&[("key", key)]), *count as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_rejected_temperatures_total",
+            "counter",
+            "The number of temperature readings rejected as implausible.",
+            data.rejected_temperatures
+                .iter()
+                .map(|(key, count)| (labels(&[("key", key)]), *count as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_relay_writes_total",
+            "counter",
+            "The number of relay state changes requested by the application.",
+            data.relay_writes.iter().map(|((key, result), count)| {
+                (labels(&[("key", key), ("result", result)]), *count as f64)
+            }),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_stove_faults_total",
+            "counter",
+            "The number of stove faults (overheating or the pipe not warming up).",
+            data.stove_faults
+                .iter()
+                .map(|(fault, count)| (labels(&[("fault", fault)]), *count as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_stove_fault_active",
+            "gauge",
+            "Whether a stove fault persists (1) or has been cleared (0).",
+            data.active_stove_faults.iter().map(|(fault, active)| {
+                (labels(&[("fault", fault)]), if *active { 1.0 } else { 0.0 })
+            }),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_job_runs_total",
+            "counter",
+            "The number of scheduler job runs.",
+            data.jobs
+                .iter()
+                .map(|(job, stats)| (labels(&[("job", job)]), stats.runs as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_job_failures_total",
+            "counter",
+            "The number of failed scheduler job runs.",
+            data.jobs
+                .iter()
+                .map(|(job, stats)| (labels(&[("job", job)]), stats.failures as f64)),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_job_last_duration_seconds",
+            "gauge",
+            "The duration of the latest scheduler job run.",
+            data.jobs
+                .iter()
+                .map(|(job, stats)| (labels(&[("job", job)]), stats.last_duration.as_secs_f64())),
+        );
+        write_family(
+            &mut out,
+            "neuroheat_job_last_success_timestamp_seconds",
+            "gauge",
+            "The Unix time of the latest successful scheduler job run.",
+            data.jobs.iter().filter_map(|(job, stats)| {
+                stats
+                    .last_success
+                    .map(|at| (labels(&[("job", job)]), at.timestamp() as f64))
+            }),
+        );
+
+        out
+    }
+
+    fn update(&self, f: impl FnOnce(&mut MetricsData)) {
+        let mut data = match self.data.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut data);
+    }
+}
+
+fn write_family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::start;
+
+    fn metrics() -> Arc<Metrics> {
+        Arc::new(Metrics::new(Arc::new(ManualClock::new(start()))))
+    }
+
+    fn samples(out: &str) -> Vec<&str> {
+        out.lines().filter(|line| !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn renders_families_without_samples() {
+        let out = metrics().render();
+
+        assert!(samples(&out).is_empty());
+        assert!(out.starts_with(
+            "# HELP neuroheat_room_temperature_celsius The latest temperature reading of a room.\n\
+             # TYPE neuroheat_room_temperature_celsius gauge\n\
+             # HELP neuroheat_room_expected_temperature_celsius "
+        ));
+        assert!(out.contains("# TYPE neuroheat_job_runs_total counter\n"));
+        assert!(out.ends_with("# TYPE neuroheat_job_last_success_timestamp_seconds gauge\n"));
+    }
+
+    #[test]
+    fn renders_samples_with_labels() {
+        let metrics = metrics();
+        metrics.set_room_temperature("office", 20.5, Some(21.0));
+        metrics.set_room_temperature("kitchen", 22.0, None);
+        metrics.set_pipe_temperature(40.0);
+        metrics.set_relay_state("stove", true);
+        metrics.record_relay_write("office", false);
+        metrics.record_job_run("temperature", Duration::from_millis(250), true);
+
+        assert_eq!(
+            samples(&metrics.render()),
+            vec![
+                r#"neuroheat_room_temperature_celsius{key="kitchen"} 22"#,
+                r#"neuroheat_room_temperature_celsius{key="office"} 20.5"#,
+                r#"neuroheat_room_expected_temperature_celsius{key="office"} 21"#,
+                "neuroheat_pipe_temperature_celsius 40",
+                "neuroheat_stove_state 1",
+                r#"neuroheat_relay_writes_total{key="office",result="failure"} 1"#,
+                r#"neuroheat_job_runs_total{job="temperature"} 1"#,
+                r#"neuroheat_job_failures_total{job="temperature"} 0"#,
+                r#"neuroheat_job_last_duration_seconds{job="temperature"} 0.25"#,
+                r#"neuroheat_job_last_success_timestamp_seconds{job="temperature"} 1705320000"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(
+            labels(&[("key", "a\"b\\c\nd"), ("job", "plain")]),
+            r#"{key="a\"b\\c\nd",job="plain"}"#
+        );
+    }
+
+    #[test]
+    fn keeps_recording_after_a_panic() {
+        let metrics = metrics();
+        let poisoned = metrics.clone();
+        std::thread::spawn(move || poisoned.update(|_| panic!("poison the metrics")))
+            .join()
+            .unwrap_err();
+
+        metrics.set_pipe_temperature(40.0);
+        metrics.record_sensor_error("office");
+
+        assert_eq!(
+            samples(&metrics.render()),
+            vec![
+                "neuroheat_pipe_temperature_celsius 40",
+                r#"neuroheat_sensor_read_errors_total{key="office"} 1"#,
+            ]
+        );
+    }
+}
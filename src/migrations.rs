@@ -0,0 +1,449 @@
+use rusqlite::Connection;
+
+use crate::error::NeuroheatError;
+
+/// A forward-only change to the database schema. The schema version
+/// is stored in `PRAGMA user_version` and equals the version of the
+/// last applied migration.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// All migrations in the order they have to be applied. Never edit
+/// a released migration; add a new one instead.
+///
+/// The first migration uses `IF NOT EXISTS` so that databases created
+/// before versioning was introduced (`user_version` = 0) are adopted
+/// without changes.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create labels, temperatures and states tables",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS labels (
+              key TEXT PRIMARY KEY,
+              label TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS temperatures (
+              id INTEGER PRIMARY KEY,
+              key TEXT NOT NULL,
+              temperature REAL NOT NULL,
+              expected_temperature REAL,
+              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc')),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            CREATE INDEX IF NOT EXISTS temperatures_key_timestamp_idx
+            ON temperatures (key, timestamp);
+
+            CREATE TABLE IF NOT EXISTS states (
+              id INTEGER PRIMARY KEY,
+              key TEXT NOT NULL,
+              state INTEGER NOT NULL,
+              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc')),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+        "#,
+    },
+    Migration {
+        version: 2,
+        description: "Create overrides table",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS overrides (
+              key TEXT PRIMARY KEY,
+              temperature REAL NOT NULL,
+              created_at TEXT NOT NULL,
+              expires_at TEXT NOT NULL,
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+        "#,
+    },
+    Migration {
+        version: 3,
+        description: "Index states by key and state",
+        sql: r#"
+            CREATE INDEX IF NOT EXISTS states_key_state_idx
+            ON states (key, state);
+        "#,
+    },
+    Migration {
+        version: 4,
+        description: "Create stove decisions table",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS stove_decisions (
+              id INTEGER PRIMARY KEY,
+              decision TEXT NOT NULL,
+              reason TEXT NOT NULL,
+              pipe_temperature REAL,
+              open_area REAL NOT NULL,
+              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc'))
+            );
+
+            CREATE INDEX IF NOT EXISTS stove_decisions_decision_timestamp_idx
+            ON stove_decisions (decision, timestamp);
+        "#,
+    },
+    Migration {
+        version: 5,
+        description: "Create rejected temperatures table",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS rejected_temperatures (
+              id INTEGER PRIMARY KEY,
+              key TEXT NOT NULL,
+              temperature REAL NOT NULL,
+              reason TEXT NOT NULL,
+              timestamp TEXT NOT NULL DEFAULT (datetime('now', 'utc')),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+        "#,
+    },
+    Migration {
+        version: 6,
+        description: "Create hourly and daily aggregate tables",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS temperatures_hourly (
+              key TEXT NOT NULL,
+              timestamp TEXT NOT NULL,
+              min REAL NOT NULL,
+              avg REAL NOT NULL,
+              max REAL NOT NULL,
+              expected_temperature REAL,
+              expected_samples INTEGER NOT NULL,
+              samples INTEGER NOT NULL,
+              PRIMARY KEY (key, timestamp),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            CREATE TABLE IF NOT EXISTS temperatures_daily (
+              key TEXT NOT NULL,
+              timestamp TEXT NOT NULL,
+              min REAL NOT NULL,
+              avg REAL NOT NULL,
+              max REAL NOT NULL,
+              expected_temperature REAL,
+              expected_samples INTEGER NOT NULL,
+              samples INTEGER NOT NULL,
+              PRIMARY KEY (key, timestamp),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            CREATE TABLE IF NOT EXISTS states_hourly (
+              key TEXT NOT NULL,
+              timestamp TEXT NOT NULL,
+              last_state INTEGER NOT NULL,
+              enabled_samples INTEGER NOT NULL,
+              samples INTEGER NOT NULL,
+              enabled_seconds INTEGER NOT NULL,
+              PRIMARY KEY (key, timestamp),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            CREATE TABLE IF NOT EXISTS states_daily (
+              key TEXT NOT NULL,
+              timestamp TEXT NOT NULL,
+              last_state INTEGER NOT NULL,
+              enabled_samples INTEGER NOT NULL,
+              samples INTEGER NOT NULL,
+              enabled_seconds INTEGER NOT NULL,
+              PRIMARY KEY (key, timestamp),
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            CREATE INDEX IF NOT EXISTS states_timestamp_idx ON states (timestamp);
+            CREATE INDEX IF NOT EXISTS temperatures_timestamp_idx ON temperatures (timestamp);
+        "#,
+    },
+    Migration {
+        version: 7,
+        description: "Create current_state table",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS current_state (
+              key TEXT PRIMARY KEY,
+              temperature REAL,
+              expected_temperature REAL,
+              temperature_timestamp TEXT,
+              state INTEGER,
+              state_timestamp TEXT,
+              state_changed_at TEXT,
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+
+            INSERT INTO current_state (key, temperature, expected_temperature, temperature_timestamp)
+            SELECT key, temperature, expected_temperature, timestamp
+            FROM temperatures
+            WHERE id IN (SELECT MAX(id) FROM temperatures GROUP BY key);
+
+            INSERT INTO current_state (key, state, state_timestamp, state_changed_at)
+            SELECT
+              states.key,
+              states.state,
+              states.timestamp,
+              (
+                SELECT MIN(run.timestamp)
+                FROM states AS run
+                WHERE run.key = states.key
+                AND run.id > COALESCE((
+                  SELECT MAX(previous.id)
+                  FROM states AS previous
+                  WHERE previous.key = states.key AND previous.state != states.state
+                ), 0)
+              )
+            FROM states
+            WHERE id IN (SELECT MAX(id) FROM states GROUP BY key)
+            ON CONFLICT (key) DO UPDATE SET
+              state = excluded.state,
+              state_timestamp = excluded.state_timestamp,
+              state_changed_at = excluded.state_changed_at;
+        "#,
+    },
+    Migration {
+        version: 8,
+        description: "Create pi_controller_state table",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS pi_controller_state (
+              key TEXT PRIMARY KEY,
+              integral REAL NOT NULL,
+              duty_cycle REAL NOT NULL,
+              cycle_start TEXT NOT NULL,
+              FOREIGN KEY(key) REFERENCES labels(key)
+            );
+        "#,
+    },
+];
+
+/// The schema version this binary expects.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32, NeuroheatError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Returns the migrations that have not been applied yet. Fails if the
+/// database was created by a newer version of the application.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, NeuroheatError> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        let err_msg = format!(
+            "Database schema version {} is newer than the latest supported version {}",
+            current, latest
+        );
+        log::error!("{}", err_msg);
+        return Err(NeuroheatError::DatabaseError(err_msg));
+    }
+
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+        .collect())
+}
+
+/// Describes the pending migrations (with their SQL) without applying
+/// them.
+pub fn dry_run(conn: &Connection) -> Result<String, NeuroheatError> {
+    let current = current_version(conn)?;
+    let pending = pending(conn)?;
+
+    let mut out = format!(
+        "Current schema version: {}, latest: {}\n",
+        current,
+        latest_version()
+    );
+    if pending.is_empty() {
+        out.push_str("No pending migrations\n");
+    }
+    for migration in pending {
+        out.push_str(&format!(
+            "Pending migration {}: {}\n{}\n",
+            migration.version, migration.description, migration.sql
+        ));
+    }
+
+    Ok(out)
+}
+
+/// Applies all pending migrations in a single transaction and returns
+/// the number of applied migrations.
+pub fn migrate(conn: &Connection) -> Result<usize, NeuroheatError> {
+    let pending = pending(conn)?;
+
+    if pending.is_empty() {
+        log::debug!(
+            "Database schema is up to date (version {})",
+            latest_version()
+        );
+        return Ok(0);
+    }
+
+    let tx = conn.unchecked_transaction()?;
+
+    for migration in &pending {
+        log::info!(
+            "Applying migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        tx.execute_batch(migration.sql).map_err(|e| {
+            let err_msg = format!("Failed to apply migration {}: {}", migration.version, e);
+            log::error!("{}", err_msg);
+            NeuroheatError::DatabaseError(err_msg)
+        })?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+    }
+
+    tx.commit()?;
+
+    Ok(pending.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies the migrations up to the given version, one by one.
+    fn migrate_to(conn: &Connection, version: u32) {
+        for migration in MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version <= version)
+        {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        conn.pragma_update(None, "user_version", version).unwrap();
+    }
+
+    fn has_table(conn: &Connection, table: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    #[test]
+    fn current_state_is_backfilled_per_key() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_to(&conn, 6);
+        conn.execute_batch(
+            r#"
+            INSERT INTO labels (key, label) VALUES ('office', 'Office'), ('kitchen', 'Kitchen');
+            INSERT INTO temperatures (key, temperature, timestamp) VALUES
+              ('office', 20.0, '2024-01-01 00:00:00'),
+              ('kitchen', 22.0, '2024-01-01 00:00:00'),
+              ('office', 20.5, '2024-01-01 00:02:00');
+            INSERT INTO states (key, state, timestamp) VALUES
+              ('office', 0, '2024-01-01 00:00:00'),
+              ('office', 1, '2024-01-01 00:15:00'),
+              ('office', 1, '2024-01-01 00:30:00'),
+              ('kitchen', 0, '2024-01-01 00:45:00');
+            "#,
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                r#"
+                SELECT key, temperature, state, state_changed_at
+                FROM current_state
+                ORDER BY key
+                "#,
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, f32>(1)?,
+                    row.get::<_, i32>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "kitchen".to_string(),
+                    22.0,
+                    0,
+                    "2024-01-01 00:45:00".to_string()
+                ),
+                (
+                    "office".to_string(),
+                    20.5,
+                    1,
+                    "2024-01-01 00:15:00".to_string()
+                ),
+            ]
+        );
+    }
+    #[test]
+    fn refuses_a_newer_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+
+        assert!(pending(&conn).is_err());
+        assert!(migrate(&conn).is_err());
+        assert!(dry_run(&conn).is_err());
+        assert_eq!(current_version(&conn).unwrap(), latest_version() + 1);
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_to(&conn, 5);
+        // Makes the backfill of migration 7 fail after migration 6 applied.
+        conn.execute_batch("CREATE TABLE current_state (key TEXT PRIMARY KEY)")
+            .unwrap();
+
+        let error = migrate(&conn).unwrap_err();
+        assert!(error.to_string().contains("migration 7"), "{}", error);
+        assert_eq!(current_version(&conn).unwrap(), 5);
+        assert!(!has_table(&conn, "temperatures_hourly"));
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len() - 5);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let path =
+            std::env::temp_dir().join(format!("neuroheat-dry-run-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        migrate_to(&Connection::open(&path).unwrap(), 6);
+
+        let conn = crate::db::open_read_only(path.to_str().unwrap()).unwrap();
+        let plan = dry_run(&conn).unwrap();
+        drop(conn);
+
+        assert!(plan.starts_with(&format!(
+            "Current schema version: 6, latest: {}\n",
+            latest_version()
+        )));
+        assert!(plan.contains("Pending migration 7: Create current_state table\n"));
+        assert!(plan.contains("CREATE TABLE IF NOT EXISTS current_state"));
+        assert!(!plan.contains("Pending migration 6"));
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 6);
+        assert!(!has_table(&conn, "current_state"));
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert!(dry_run(&conn).unwrap().ends_with("No pending migrations\n"));
+    }
+}
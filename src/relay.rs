@@ -1,11 +1,15 @@
 use rusqlite::Connection;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use crate::error::NeuroheatError;
+use crate::events::Events;
 use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
 use crate::repo;
 
 const GPIO_PATH_PREFIX: &str = "/sys/class/gpio";
@@ -16,30 +20,46 @@ pub trait RelayController: std::fmt::Debug + Send + Sync {
     fn setup(&self) -> Result<(), NeuroheatError>;
 }
 
+/// Controls a relay through the `/sys/class/gpio` interface. For active-low
+/// relays, the states are inverted when reading and writing the pin value
+/// (the sysfs `active_low` attribute is left untouched so that the helper
+/// scripts keep working with raw levels).
 #[derive(Debug)]
 pub struct GPIOController {
     pin: u8,
+    active_low: bool,
+    root: PathBuf,
 }
 
 impl GPIOController {
-    pub fn new(pin: u8) -> Self {
-        GPIOController { pin }
+    pub fn new(pin: u8, active_low: bool) -> Self {
+        Self::with_root(GPIO_PATH_PREFIX, pin, active_low)
+    }
+
+    /// Uses the sysfs GPIO tree under the given directory.
+    pub fn with_root(root: impl Into<PathBuf>, pin: u8, active_low: bool) -> Self {
+        GPIOController {
+            pin,
+            active_low,
+            root: root.into(),
+        }
+    }
+
+    fn pin_path(&self, attribute: &str) -> PathBuf {
+        self.root.join(format!("gpio{}", self.pin)).join(attribute)
     }
 }
 
 impl RelayController for GPIOController {
     fn read_state(&self) -> Result<bool, NeuroheatError> {
-        let path_str = format!("{}/gpio{}/value", GPIO_PATH_PREFIX, self.pin);
-        let path = Path::new(&path_str);
-
-        let file = File::open(&path)?;
+        let file = File::open(self.pin_path("value"))?;
         let mut reader = io::BufReader::new(file);
         let mut state_str = String::new();
         reader.read_line(&mut state_str)?;
 
         match state_str.trim() {
-            "0" => Ok(false),
-            "1" => Ok(true),
+            "0" => Ok(self.active_low),
+            "1" => Ok(!self.active_low),
             _ => {
                 let err_msg = "Invalid state value".to_string();
                 log::error!("{}", err_msg);
@@ -49,24 +69,21 @@ impl RelayController for GPIOController {
     }
 
     fn set_state(&self, state: bool) -> Result<(), NeuroheatError> {
-        let path_str = format!("{}/gpio{}/value", GPIO_PATH_PREFIX, self.pin);
-        let path = Path::new(&path_str);
-
         log::debug!("Setting GPIO pin {} to state {}", self.pin, state);
 
-        let mut file = File::create(&path)?;
-        let state_str = if state { "1" } else { "0" };
+        let mut file = File::create(self.pin_path("value"))?;
+        let state_str = if state != self.active_low { "1" } else { "0" };
         file.write_all(state_str.as_bytes())?;
 
         Ok(())
     }
 
     fn setup(&self) -> Result<(), NeuroheatError> {
-        let export_path = format!("{}/export", GPIO_PATH_PREFIX);
-        let direction_path = format!("{}/gpio{}/direction", GPIO_PATH_PREFIX, self.pin);
-        let gpio_path = format!("{}/gpio{}", GPIO_PATH_PREFIX, self.pin);
+        let export_path = self.root.join("export");
+        let direction_path = self.pin_path("direction");
+        let gpio_path = self.root.join(format!("gpio{}", self.pin));
 
-        if Path::new(&gpio_path).exists() {
+        if gpio_path.exists() {
             log::debug!("Pin {} is already exported", self.pin);
         } else {
             log::info!("Exporting pin {}", self.pin);
@@ -74,11 +91,13 @@ impl RelayController for GPIOController {
             file.write_all(self.pin.to_string().as_bytes())?;
         }
 
-        let direction = "out";
         let current_direction = std::fs::read_to_string(&direction_path).unwrap_or_default();
-        if current_direction.trim() == direction {
-            log::debug!("Pin {} is already set as {}", self.pin, direction);
+        if current_direction.trim() == "out" {
+            log::debug!("Pin {} is already set as out", self.pin);
         } else {
+            // `out` drives the pin low, which energizes an active-low relay.
+            // `high` sets the direction and an inactive level in one write.
+            let direction = if self.active_low { "high" } else { "out" };
             log::info!("Setting up pin {} as {}", self.pin, direction);
             let mut file = File::create(&direction_path)?;
             file.write_all(direction.as_bytes())?;
@@ -88,6 +107,250 @@ impl RelayController for GPIOController {
     }
 }
 
+/// The GPIO character device uAPI (v2) as defined in `linux/gpio.h`.
+mod gpio_v2 {
+    pub const LINES_MAX: usize = 64;
+    pub const LINE_NUM_ATTRS_MAX: usize = 10;
+    pub const MAX_NAME_SIZE: usize = 32;
+
+    pub const LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+    pub const LINE_FLAG_OUTPUT: u64 = 1 << 3;
+
+    #[repr(C)]
+    pub struct LineAttribute {
+        pub id: u32,
+        pub padding: u32,
+        pub value: u64,
+    }
+
+    #[repr(C)]
+    pub struct LineConfigAttribute {
+        pub attr: LineAttribute,
+        pub mask: u64,
+    }
+
+    #[repr(C)]
+    pub struct LineConfig {
+        pub flags: u64,
+        pub num_attrs: u32,
+        pub padding: [u32; 5],
+        pub attrs: [LineConfigAttribute; LINE_NUM_ATTRS_MAX],
+    }
+
+    #[repr(C)]
+    pub struct LineRequest {
+        pub offsets: [u32; LINES_MAX],
+        pub consumer: [u8; MAX_NAME_SIZE],
+        pub config: LineConfig,
+        pub num_lines: u32,
+        pub event_buffer_size: u32,
+        pub padding: [u32; 5],
+        pub fd: i32,
+    }
+
+    #[repr(C)]
+    pub struct LineValues {
+        pub bits: u64,
+        pub mask: u64,
+    }
+
+    const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+    const _: () = assert!(std::mem::size_of::<LineValues>() == 16);
+
+    const fn iowr(nr: u32, size: usize) -> u32 {
+        (3 << 30) | ((size as u32) << 16) | (0xB4 << 8) | nr
+    }
+
+    pub const GET_LINE_IOCTL: u32 = iowr(0x07, std::mem::size_of::<LineRequest>());
+    pub const LINE_GET_VALUES_IOCTL: u32 = iowr(0x0E, std::mem::size_of::<LineValues>());
+    pub const LINE_SET_VALUES_IOCTL: u32 = iowr(0x0F, std::mem::size_of::<LineValues>());
+}
+
+/// Controls a relay through a GPIO character device (e.g., `/dev/gpiochip0`)
+/// using the v2 line request API. The line is requested as an output in
+/// `setup` and held for the lifetime of the process; the kernel releases it
+/// (and the line may return to its default level) when the process exits.
+/// Active-low lines are inverted by the kernel.
+#[derive(Debug)]
+pub struct GPIOCharDeviceController {
+    chip: String,
+    line: u8,
+    active_low: bool,
+    request: Mutex<Option<File>>,
+}
+
+impl GPIOCharDeviceController {
+    pub fn new(chip: String, line: u8, active_low: bool) -> Self {
+        GPIOCharDeviceController {
+            chip,
+            line,
+            active_low,
+            request: Mutex::new(None),
+        }
+    }
+
+    fn with_request<T>(
+        &self,
+        f: impl FnOnce(&File) -> Result<T, NeuroheatError>,
+    ) -> Result<T, NeuroheatError> {
+        let request = self.request.lock().map_err(|e| {
+            NeuroheatError::RelayError(format!(
+                "Failed to lock line {} of {}: {}",
+                self.line, self.chip, e
+            ))
+        })?;
+
+        match request.as_ref() {
+            Some(file) => f(file),
+            None => Err(NeuroheatError::RelayError(format!(
+                "Line {} of {} has not been requested",
+                self.line, self.chip
+            ))),
+        }
+    }
+
+    /// Builds the request for the line as an output.
+    fn line_request(&self) -> gpio_v2::LineRequest {
+        // SAFETY: the structure consists of integers and arrays only,
+        // for which all-zero bytes are a valid value.
+        let mut line_request: gpio_v2::LineRequest = unsafe { std::mem::zeroed() };
+        line_request.offsets[0] = self.line as u32;
+        line_request.num_lines = 1;
+        line_request.config.flags = gpio_v2::LINE_FLAG_OUTPUT;
+        if self.active_low {
+            line_request.config.flags |= gpio_v2::LINE_FLAG_ACTIVE_LOW;
+        }
+        let consumer = env!("CARGO_PKG_NAME").as_bytes();
+        line_request.consumer[..consumer.len()].copy_from_slice(consumer);
+        line_request
+    }
+
+    fn ioctl<T>(&self, file: &File, request: u32, data: &mut T) -> Result<(), NeuroheatError> {
+        // SAFETY: `data` points to a properly sized `repr(C)` structure
+        // matching the ioctl request number.
+        let result =
+            unsafe { libc::ioctl(file.as_raw_fd(), request as libc::Ioctl, data as *mut T) };
+
+        if result < 0 {
+            let err_msg = format!(
+                "GPIO ioctl failed for line {} of {}: {}",
+                self.line,
+                self.chip,
+                io::Error::last_os_error()
+            );
+            log::error!("{}", err_msg);
+            return Err(NeuroheatError::RelayError(err_msg));
+        }
+
+        Ok(())
+    }
+}
+
+impl RelayController for GPIOCharDeviceController {
+    fn read_state(&self) -> Result<bool, NeuroheatError> {
+        self.with_request(|file| {
+            let mut values = gpio_v2::LineValues { bits: 0, mask: 1 };
+            self.ioctl(file, gpio_v2::LINE_GET_VALUES_IOCTL, &mut values)?;
+            Ok(values.bits & 1 == 1)
+        })
+    }
+
+    fn set_state(&self, state: bool) -> Result<(), NeuroheatError> {
+        log::debug!(
+            "Setting line {} of {} to state {}",
+            self.line,
+            self.chip,
+            state
+        );
+
+        self.with_request(|file| {
+            let mut values = gpio_v2::LineValues {
+                bits: state as u64,
+                mask: 1,
+            };
+            self.ioctl(file, gpio_v2::LINE_SET_VALUES_IOCTL, &mut values)
+        })
+    }
+
+    fn setup(&self) -> Result<(), NeuroheatError> {
+        let mut request = self.request.lock().map_err(|e| {
+            NeuroheatError::RelayError(format!(
+                "Failed to lock line {} of {}: {}",
+                self.line, self.chip, e
+            ))
+        })?;
+
+        if request.is_some() {
+            log::debug!("Line {} of {} is already requested", self.line, self.chip);
+            return Ok(());
+        }
+
+        log::info!("Requesting line {} of {} as output", self.line, self.chip);
+
+        let chip = OpenOptions::new().read(true).write(true).open(&self.chip)?;
+        let mut line_request = self.line_request();
+        self.ioctl(&chip, gpio_v2::GET_LINE_IOCTL, &mut line_request)?;
+
+        // SAFETY: on success, the kernel returns a new file descriptor
+        // for the requested line that nothing else owns.
+        let line = unsafe { OwnedFd::from_raw_fd(line_request.fd) };
+        *request = Some(File::from(line));
+
+        Ok(())
+    }
+}
+
+/// An in-memory relay. Used in tests and the simulator instead of the GPIO
+/// pins; tests can make it fail to exercise error handling.
+#[derive(Debug, Default)]
+pub struct VirtualRelayController {
+    state: Mutex<bool>,
+    failing: AtomicBool,
+    writes: AtomicUsize,
+}
+
+impl VirtualRelayController {
+    /// Makes reads and writes fail (or succeed again).
+    #[cfg(test)]
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+
+    /// The number of successful writes, including those that did not change
+    /// the state.
+    #[cfg(test)]
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    fn check_failing(&self) -> Result<(), NeuroheatError> {
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(NeuroheatError::RelayError(
+                "Virtual relay is failing".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl RelayController for VirtualRelayController {
+    fn read_state(&self) -> Result<bool, NeuroheatError> {
+        self.check_failing()?;
+        Ok(*self.state.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn set_state(&self, state: bool) -> Result<(), NeuroheatError> {
+        self.check_failing()?;
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = state;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn setup(&self) -> Result<(), NeuroheatError> {
+        self.check_failing()
+    }
+}
+
 pub fn setup_all_relays(config: &HeatingConfiguration) -> Result<(), NeuroheatError> {
     if let Some(stove_reader) = &config.stove_reader {
         stove_reader.setup()?;
@@ -102,17 +365,74 @@ pub fn setup_all_relays(config: &HeatingConfiguration) -> Result<(), NeuroheatEr
     Ok(())
 }
 
+/// Drives all relays to the configured safe state and records it. The stove
+/// is switched first so that it never runs against valves being closed.
+pub fn apply_safe_state(
+    config: &HeatingConfiguration,
+    conn: &Arc<Mutex<Connection>>,
+    metrics: &Metrics,
+    events: &Events,
+) -> Result<(), NeuroheatError> {
+    let mut result = Ok(());
+
+    let relays = config
+        .stove_reader
+        .iter()
+        .map(|relay| ("stove", "Stove", relay, config.safe_state.stove))
+        .chain(config.rooms.iter().filter_map(|room| {
+            room.valve_reader.as_ref().map(|relay| {
+                (
+                    room.key.as_str(),
+                    room.name.as_str(),
+                    relay,
+                    config.safe_state.valves,
+                )
+            })
+        }));
+
+    for (key, name, relay, state) in relays {
+        log::info!(
+            "{}: Applying safe state {}",
+            name,
+            if state { "ON" } else { "OFF" }
+        );
+
+        let write_result = relay.set_state(state);
+        metrics.record_relay_write(key, write_result.is_ok());
+        if let Err(e) = write_result {
+            log::error!("Failed to apply safe state for {}: {}", name, e);
+            result = Err(e);
+            continue;
+        }
+        let now = config.clock.now();
+        metrics.set_relay_state(key, state);
+        events.relay_state(key, state, now);
+        if let Err(e) = repo::store_state(conn, key, state, now) {
+            log::error!("Failed to store safe state for {}: {}", name, e);
+            result = Err(e);
+        }
+    }
+
+    result
+}
+
 pub async fn read_relay_states(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
 ) -> Result<(), NeuroheatError> {
+    let now = config.clock.now();
+
     // Read stove state
     if let Some(stove_reader) = &config.stove_reader {
         match stove_reader.read_state() {
             Ok(state) => {
                 log::info!("Stove State: {}", state);
-                if let Err(e) = repo::store_state(&conn, "stove", state) {
-                    log::error!("Failed to store stove state: {}", e);
+                metrics.set_relay_state("stove", state);
+                match repo::store_state(&conn, "stove", state, now) {
+                    Ok(()) => events.relay_state("stove", state, now),
+                    Err(e) => log::error!("Failed to store stove state: {}", e),
                 }
             }
             Err(e) => {
@@ -126,8 +446,12 @@ pub async fn read_relay_states(
             match valve_reader.read_state() {
                 Ok(state) => {
                     log::info!("Room: {}, Valve State: {}", room.name, state);
-                    if let Err(e) = repo::store_state(&conn, &room.key, state) {
-                        log::error!("Failed to store valve state for room {}: {}", room.name, e);
+                    metrics.set_relay_state(&room.key, state);
+                    match repo::store_state(&conn, &room.key, state, now) {
+                        Ok(()) => events.relay_state(&room.key, state, now),
+                        Err(e) => {
+                            log::error!("Failed to store valve state for room {}: {}", room.name, e)
+                        }
                     }
                 }
                 Err(e) => {
@@ -141,3 +465,127 @@ pub async fn read_relay_states(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::testing::{Harness, STOVE_PIN};
+    use std::path::Path;
+
+    /// A sysfs GPIO tree with an exported pin configured as an input.
+    fn sysfs(name: &str, pin: u8) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("neuroheat-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(format!("gpio{}", pin))).unwrap();
+        std::fs::write(root.join(format!("gpio{}/direction", pin)), "in\n").unwrap();
+        root
+    }
+
+    fn direction(root: &Path, pin: u8) -> String {
+        std::fs::read_to_string(root.join(format!("gpio{}/direction", pin))).unwrap()
+    }
+
+    #[test]
+    fn sets_up_an_active_low_pin_with_an_inactive_level() {
+        let root = sysfs("active-low", 5);
+
+        GPIOController::with_root(&root, 5, true).setup().unwrap();
+
+        assert_eq!(direction(&root, 5), "high");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sets_up_an_active_high_pin_as_output() {
+        let root = sysfs("active-high", 6);
+
+        GPIOController::with_root(&root, 6, false).setup().unwrap();
+
+        assert_eq!(direction(&root, 6), "out");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_level_of_a_configured_pin() {
+        let root = sysfs("configured", 7);
+        std::fs::write(root.join("gpio7/direction"), "out\n").unwrap();
+
+        GPIOController::with_root(&root, 7, true).setup().unwrap();
+
+        assert_eq!(direction(&root, 7), "out\n");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn matches_the_gpio_v2_struct_layout() {
+        use gpio_v2::*;
+        use std::mem::{offset_of, size_of};
+
+        // sizes and offsets from linux/gpio.h
+        assert_eq!(size_of::<LineAttribute>(), 16);
+        assert_eq!(size_of::<LineConfigAttribute>(), 24);
+        assert_eq!(size_of::<LineConfig>(), 272);
+        assert_eq!(size_of::<LineRequest>(), 592);
+        assert_eq!(size_of::<LineValues>(), 16);
+        assert_eq!(offset_of!(LineRequest, consumer), 256);
+        assert_eq!(offset_of!(LineRequest, config), 288);
+        assert_eq!(offset_of!(LineRequest, num_lines), 560);
+        assert_eq!(offset_of!(LineRequest, fd), 588);
+    }
+
+    #[test]
+    fn matches_the_gpio_v2_ioctl_numbers() {
+        assert_eq!(gpio_v2::GET_LINE_IOCTL, 0xC250_B407);
+        assert_eq!(gpio_v2::LINE_GET_VALUES_IOCTL, 0xC010_B40E);
+        assert_eq!(gpio_v2::LINE_SET_VALUES_IOCTL, 0xC010_B40F);
+    }
+
+    #[test]
+    fn requests_an_output_line_with_the_active_low_flag() {
+        assert_eq!(gpio_v2::LINE_FLAG_ACTIVE_LOW, 0x2);
+        assert_eq!(gpio_v2::LINE_FLAG_OUTPUT, 0x8);
+
+        let active_high = GPIOCharDeviceController::new("/dev/gpiochip0".into(), 17, false);
+        let request = active_high.line_request();
+        assert_eq!(request.config.flags, gpio_v2::LINE_FLAG_OUTPUT);
+        assert_eq!(request.offsets[0], 17);
+        assert_eq!(request.num_lines, 1);
+        assert!(request.consumer.starts_with(b"neuroheat\0"));
+
+        let active_low = GPIOCharDeviceController::new("/dev/gpiochip0".into(), 17, true);
+        assert_eq!(
+            active_low.line_request().config.flags,
+            gpio_v2::LINE_FLAG_OUTPUT | gpio_v2::LINE_FLAG_ACTIVE_LOW
+        );
+    }
+
+    #[test]
+    fn publishes_the_safe_state() {
+        let harness = Harness::new();
+        let mut events = harness.events.subscribe();
+        harness.relay(STOVE_PIN).set_state(true).unwrap();
+
+        apply_safe_state(
+            &harness.config,
+            &harness.conn,
+            &harness.metrics,
+            &harness.events,
+        )
+        .unwrap();
+
+        assert!(!harness.relay(STOVE_PIN).read_state().unwrap());
+        assert_eq!(harness.stored_state("stove"), Some(false));
+        let mut keys = Vec::new();
+        while let Ok(Event::RelayState {
+            key,
+            heating_enabled,
+            ..
+        }) = events.try_recv()
+        {
+            assert!(!heating_enabled);
+            keys.push(key);
+        }
+        assert_eq!(keys, ["stove", "office", "kitchen"]);
+    }
+}
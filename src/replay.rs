@@ -0,0 +1,337 @@
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::ManualClock;
+use crate::controller::OUTDOOR_KEY;
+use crate::db;
+use crate::devices::VirtualDevices;
+use crate::error::NeuroheatError;
+use crate::events::Events;
+use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
+use crate::relay::RelayController;
+use crate::repo;
+use crate::report::{Comparison, Report};
+use crate::scheduler::SimulatedScheduler;
+
+/// How far the replayed time advances per step. The default cron
+/// expressions all run at multiples of 15 seconds.
+const STEP_SECONDS: i64 = 15;
+
+/// The recorded values of one key, looked up in time order.
+struct Recording<T> {
+    samples: Vec<(DateTime<Utc>, T)>,
+    next: usize,
+}
+
+impl<T: Copy> Recording<T> {
+    fn new() -> Self {
+        Recording {
+            samples: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns the latest value recorded at or before the given time. The
+    /// time must not go back between calls.
+    fn at(&mut self, time: DateTime<Utc>) -> Option<(DateTime<Utc>, T)> {
+        while self.next < self.samples.len() && self.samples[self.next].0 <= time {
+            self.next += 1;
+        }
+        self.next.checked_sub(1).map(|i| self.samples[i])
+    }
+}
+
+/// Feeds the temperatures recorded between `from` and `to` through the
+/// controller with a candidate configuration and compares its valve and
+/// stove decisions with the recorded ones.
+///
+/// The recorded temperatures are replayed as they are: they do not react to
+/// the decisions of the candidate. Periods that were downsampled are
+/// replayed from their hourly or daily averages. Temperature overrides are
+/// not replayed either.
+pub async fn replay(
+    mut config: HeatingConfiguration,
+    recorded: &Arc<Mutex<Connection>>,
+    conn: Arc<Mutex<Connection>>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Comparison, NeuroheatError> {
+    if from >= to {
+        let err_msg = format!("The replay must start before it ends: {} - {}", from, to);
+        log::error!("{}", err_msg);
+        return Err(NeuroheatError::ValidationError(err_msg));
+    }
+
+    let max_reading_age = Duration::minutes(config.health.max_reading_age_minutes as i64);
+    let mut temperatures = HashMap::new();
+    for (reading, length) in repo::get_recorded_temperatures(recorded, from - max_reading_age, to)?
+    {
+        temperatures
+            .entry(reading.key)
+            .or_insert_with(Recording::new)
+            .samples
+            .push((
+                reading.timestamp,
+                (reading.temperature, reading.expected_temperature, length),
+            ));
+    }
+    if temperatures.is_empty() {
+        let err_msg = format!("No recorded temperatures between {} and {}", from, to);
+        log::error!("{}", err_msg);
+        return Err(NeuroheatError::ValidationError(err_msg));
+    }
+    let mut states = HashMap::new();
+    for (key, timestamp, state) in repo::get_recorded_states(recorded, from, to)? {
+        states
+            .entry(key)
+            .or_insert_with(Recording::new)
+            .samples
+            .push((timestamp, state));
+    }
+
+    // The recorded pipe temperatures do not react to the candidate's stove,
+    // so a pipe that does not warm up is not a fault here.
+    config.controller.min_pipe_temperature_rise = f32::NEG_INFINITY;
+
+    let devices = VirtualDevices::new();
+    let clock = Arc::new(ManualClock::new(from));
+    config.attach_devices(&devices);
+    config.clock = clock.clone();
+    let config = Arc::new(config);
+    let metrics = Arc::new(Metrics::new(config.clock.clone()));
+    let events = Arc::new(Events::new());
+
+    db::init(&conn, &config)?;
+
+    // The candidate starts with the valves and the stove as they were.
+    let relays = config
+        .rooms
+        .iter()
+        .map(|room| (room.key.as_str(), room.valve_pin))
+        .chain(std::iter::once(("stove", config.stove_pin)));
+    for (key, pin) in relays {
+        let (timestamp, state) = states
+            .get_mut(key)
+            .and_then(|recording| recording.at(from))
+            .unwrap_or((from, false));
+        devices.relay_at(pin).set_state(state)?;
+        repo::store_state(&conn, key, state, timestamp)?;
+    }
+
+    let sensors: Vec<(&str, &str)> = config
+        .rooms
+        .iter()
+        .map(|room| (room.key.as_str(), room.sensor_id.as_str()))
+        .chain(std::iter::once(("pipe", config.pipe_sensor_id.as_str())))
+        .chain(
+            config
+                .outdoor_sensor_id
+                .as_deref()
+                .map(|sensor_id| (OUTDOOR_KEY, sensor_id)),
+        )
+        .collect();
+    for (key, _) in &sensors {
+        let Some(recording) = temperatures.get(*key) else {
+            log::warn!("No recorded temperatures for {}", key);
+            continue;
+        };
+        // The controller needs recent readings from the start.
+        for (timestamp, (temperature, expected_temperature, _)) in &recording.samples {
+            if *timestamp >= from {
+                break;
+            }
+            repo::store_temperature(&conn, key, *temperature, *expected_temperature, *timestamp)?;
+        }
+    }
+
+    let mut scheduler = SimulatedScheduler::new(&config, from)?;
+    let mut actual = Report::new(&config, from);
+    let mut candidate = Report::new(&config, from);
+
+    let step = Duration::seconds(STEP_SECONDS);
+    let mut now = from;
+
+    while now < to {
+        now += step;
+        clock.set(now);
+
+        let mut reading_at = |key: &str| {
+            temperatures
+                .get_mut(key)
+                .and_then(|recording| recording.at(now))
+                // A downsampled reading stands for its whole bucket.
+                .filter(|(timestamp, (_, _, length))| now - *timestamp <= max_reading_age + *length)
+                .map(|(_, (temperature, expected_temperature, _))| {
+                    (temperature, expected_temperature)
+                })
+        };
+
+        let mut readings = HashMap::new();
+        for (key, sensor_id) in &sensors {
+            let reading = reading_at(key);
+            devices
+                .sensor(sensor_id)
+                .set(reading.map(|(temperature, _)| temperature));
+            readings.insert(*key, reading);
+        }
+
+        scheduler
+            .run_due(now, &config, &conn, &metrics, &events)
+            .await?;
+
+        let outdoor_temperature = readings
+            .get(OUTDOOR_KEY)
+            .copied()
+            .flatten()
+            .map(|(temperature, _)| temperature);
+        let mut recorded_state = |key: &str| {
+            states
+                .get_mut(key)
+                .and_then(|recording| recording.at(now))
+                .is_some_and(|(_, state)| state)
+        };
+
+        for room in &config.rooms {
+            let Some(Some((temperature, expected_temperature))) = readings.get(room.key.as_str())
+            else {
+                continue;
+            };
+            actual.record_room(
+                &room.key,
+                *temperature,
+                *expected_temperature,
+                recorded_state(&room.key),
+                step,
+            );
+            candidate.record_room(
+                &room.key,
+                *temperature,
+                room.get_expected_temperature(None, outdoor_temperature, now),
+                devices.relay_at(room.valve_pin).read_state()?,
+                step,
+            );
+        }
+        actual.record_stove(recorded_state("stove"), step);
+        candidate.record_stove(devices.relay_at(config.stove_pin).read_state()?, step);
+    }
+    actual.to = now;
+    candidate.to = now;
+
+    Ok(Comparison { actual, candidate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, start};
+
+    /// Records two hours of a house kept at 18 °C: the office at 19 °C, the
+    /// kitchen at 21.5 °C, with the valves and the stove off.
+    fn recorded_house() -> Arc<Mutex<Connection>> {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        db::init(&conn, &config()).unwrap();
+
+        for minute in (-10..120).step_by(2) {
+            let timestamp = start() + Duration::minutes(minute);
+            repo::store_temperature(&conn, "office", 19.0, Some(18.0), timestamp).unwrap();
+            repo::store_temperature(&conn, "kitchen", 21.5, Some(18.0), timestamp).unwrap();
+            repo::store_temperature(&conn, "pipe", 40.0, None, timestamp).unwrap();
+            if minute % 15 == 0 {
+                for key in ["office", "kitchen", "stove"] {
+                    repo::store_state(&conn, key, false, timestamp).unwrap();
+                }
+            }
+        }
+        conn
+    }
+
+    fn room<'a>(report: &'a Report, key: &str) -> &'a crate::report::RoomReport {
+        report.rooms.iter().find(|room| room.key == key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compares_the_candidate_with_the_recorded_decisions() {
+        let recorded = recorded_house();
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+
+        let comparison = replay(
+            config(),
+            &recorded,
+            conn,
+            start(),
+            start() + Duration::hours(2),
+        )
+        .await
+        .unwrap();
+        let (actual, candidate) = (&comparison.actual, &comparison.candidate);
+
+        assert_eq!(actual.stove.on_minutes, 0.0);
+        assert_eq!(actual.stove.starts, 0);
+        assert!(candidate.stove.on_minutes > 60.0);
+        assert_eq!(candidate.stove.starts, 1);
+
+        assert_eq!(room(actual, "office").valve.on_minutes, 0.0);
+        assert!(room(candidate, "office").valve.on_minutes > 60.0);
+        assert_eq!(room(candidate, "kitchen").valve.on_minutes, 0.0);
+
+        // The office was warm enough for the recorded setpoint, not for the
+        // candidate's.
+        assert_eq!(room(actual, "office").below_setpoint_minutes, 0.0);
+        assert_eq!(room(candidate, "office").below_setpoint_minutes, 120.0);
+        assert_eq!(room(candidate, "kitchen").below_setpoint_minutes, 0.0);
+    }
+
+    #[tokio::test]
+    async fn replays_downsampled_periods() {
+        let recorded = recorded_house();
+        repo::store_state(&recorded, "stove", true, start() + Duration::minutes(30)).unwrap();
+        repo::store_state(&recorded, "stove", false, start() + Duration::minutes(50)).unwrap();
+        repo::downsample(
+            &recorded,
+            start() + Duration::hours(3),
+            start() - Duration::days(1),
+        )
+        .unwrap();
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+
+        let comparison = replay(
+            config(),
+            &recorded,
+            conn,
+            start(),
+            start() + Duration::hours(2),
+        )
+        .await
+        .unwrap();
+        let (actual, candidate) = (&comparison.actual, &comparison.candidate);
+
+        // The stove was on for 20 minutes of the first hour (within a step).
+        assert!((actual.stove.on_minutes - 20.0).abs() <= 0.25);
+        assert!(candidate.stove.on_minutes > 60.0);
+
+        // The hourly averages cover the whole range.
+        assert_eq!(room(actual, "office").below_setpoint_minutes, 0.0);
+        assert_eq!(room(candidate, "office").below_setpoint_minutes, 120.0);
+        assert_eq!(room(candidate, "kitchen").below_setpoint_minutes, 0.0);
+    }
+
+    #[tokio::test]
+    async fn requires_recorded_temperatures() {
+        let recorded = recorded_house();
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+
+        let result = replay(
+            config(),
+            &recorded,
+            conn,
+            start() + Duration::days(1),
+            start() + Duration::days(2),
+        )
+        .await;
+
+        assert!(matches!(result, Err(NeuroheatError::ValidationError(_))));
+    }
+}
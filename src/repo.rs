@@ -1,104 +1,56 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
-use rusqlite::{params, Connection};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
 use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
 
+use crate::controller::StoveDecision;
 use crate::db;
 use crate::error::NeuroheatError;
+use crate::history::{
+    self, HistoryQuery, StateBucket, StatePeriod, TemperatureBucket, MAX_STATE_SECONDS,
+};
+use crate::state::{KeyState, TemperatureReading};
+use crate::temperature_override::TemperatureOverride;
+use crate::valve_strategy::PiState;
 
 pub fn get_current_state(
     conn: &Arc<Mutex<Connection>>,
-) -> Result<BTreeMap<String, HashMap<&'static str, String>>, NeuroheatError> {
+) -> Result<BTreeMap<String, KeyState>, NeuroheatError> {
     db::with_locked_connection(conn, |conn| {
         let mut stmt = conn.prepare(
             r#"
             SELECT
-              temperatures.key,
-              COALESCE(labels.label, temperatures.key),
-              temperatures.timestamp,
-              temperatures.temperature,
-              temperatures.expected_temperature,
-              states.state
-            FROM labels
-            LEFT JOIN temperatures ON labels.key = temperatures.key
-            AND temperatures.timestamp = (
-              SELECT MAX(timestamp)
-              FROM temperatures
-              WHERE key = labels.key
-            )
-            LEFT JOIN states ON labels.key = states.key
-            AND states.timestamp = (
-              SELECT MAX(timestamp)
-              FROM states
-              WHERE key = states.key
-            )
-            WHERE temperatures.key IS NOT NULL
+              current_state.key,
+              COALESCE(labels.label, current_state.key),
+              COALESCE(current_state.temperature_timestamp, current_state.state_timestamp),
+              current_state.temperature,
+              current_state.expected_temperature,
+              current_state.state
+            FROM current_state
+            LEFT JOIN labels ON labels.key = current_state.key
             "#,
         )?;
 
-        let mut result = stmt
+        let result = stmt
             .query_map([], |row| {
                 let key = row.get::<_, String>(0)?;
-                let label = row.get::<_, String>(1)?;
-                let timestamp_str = row.get::<_, String>(2)?;
-                let timestamp = NaiveDateTime::parse_from_str(&timestamp_str, "%Y-%m-%d %H:%M:%S")
-                    .map(|dt| dt.and_utc().to_string())
-                    .unwrap_or_else(|_| timestamp_str);
-                let temperature = row.get::<_, f32>(3)?.to_string();
-                let mut map = HashMap::from([
-                    ("label", label),
-                    ("timestamp", timestamp),
-                    ("temperature", temperature),
-                ]);
-                if let Some(expected_temp) = row.get::<_, Option<f32>>(4)? {
-                    map.insert("expected_temperature", expected_temp.to_string());
-                }
-                if let Some(state) = row.get::<_, Option<i32>>(5)? {
-                    map.insert("heating_enabled", (state != 0).to_string());
-                }
-                Ok((key, map))
+                let state = KeyState {
+                    label: row.get(1)?,
+                    timestamp: row_timestamp(row, 2)?,
+                    temperature: row.get(3)?,
+                    expected_temperature: row.get(4)?,
+                    heating_enabled: row.get::<_, Option<i32>>(5)?.map(|state| state != 0),
+                };
+                Ok((key, state))
             })?
             .collect::<Result<BTreeMap<_, _>, _>>()?;
 
-        let stove_state = conn.query_row(
-            r#"
-            SELECT
-              states.key,
-              COALESCE(labels.label, states.key),
-              states.timestamp,
-              states.state
-            FROM states
-            LEFT JOIN labels ON labels.key = states.key
-            WHERE states.key = 'stove'
-            ORDER BY states.timestamp DESC
-            LIMIT 1
-            "#,
-            [],
-            |row| {
-                let key = row.get::<_, String>(0)?;
-                let label = row.get::<_, String>(1)?;
-                let timestamp_str = row.get::<_, String>(2)?;
-                let timestamp = NaiveDateTime::parse_from_str(&timestamp_str, "%Y-%m-%d %H:%M:%S")
-                    .map(|dt| dt.and_utc().to_string())
-                    .unwrap_or_else(|_| timestamp_str);
-                let state = (row.get::<_, i32>(3)? != 0).to_string();
-                let map = HashMap::from([
-                    ("label", label),
-                    ("timestamp", timestamp),
-                    ("heating_enabled", state),
-                ]);
-                Ok((key, map))
-            },
-        )?;
-
-        result.insert("stove".to_string(), stove_state.1);
-
         Ok(result)
     })
     .map_err(|e| {
         let err_msg = format!("Failed to get current state: {}", e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
 
@@ -107,69 +59,78 @@ pub fn store_temperature(
     key: &str,
     temperature: f32,
     expected_temperature: Option<f32>,
+    timestamp: DateTime<Utc>,
 ) -> Result<(), NeuroheatError> {
     db::with_locked_connection(conn, |conn| {
-        conn.execute(
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
             r#"
-            INSERT INTO temperatures (key, temperature, expected_temperature)
-            VALUES (?1, ?2, ?3)
+            INSERT INTO temperatures (key, temperature, expected_temperature, timestamp)
+            VALUES (?1, ?2, ?3, ?4)
             "#,
-            params![key, temperature, expected_temperature],
-        )
-        .map(|_| ())
+            params![
+                key,
+                temperature,
+                expected_temperature,
+                timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+            ],
+        )?;
+        tx.execute(
+            r#"
+            INSERT INTO current_state (key, temperature, expected_temperature, temperature_timestamp)
+            SELECT key, temperature, expected_temperature, timestamp
+            FROM temperatures
+            WHERE id = last_insert_rowid()
+            ON CONFLICT (key) DO UPDATE SET
+              temperature = excluded.temperature,
+              expected_temperature = excluded.expected_temperature,
+              temperature_timestamp = excluded.temperature_timestamp
+            "#,
+            [],
+        )?;
+        tx.commit()
     })
     .map_err(|e| {
         let err_msg = format!("Failed to store temperature for key {}: {}", key, e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
 
 pub fn get_latest_temperature(
     conn: &Arc<Mutex<Connection>>,
     key: &str,
-) -> Result<HashMap<&'static str, String>, NeuroheatError> {
+) -> Result<Option<TemperatureReading>, NeuroheatError> {
     db::with_locked_connection(conn, |conn| {
         conn.query_row(
             r#"
             SELECT
-              temperatures.key,
-              COALESCE(labels.label, temperatures.key),
-              temperatures.timestamp,
-              temperatures.temperature,
-              temperatures.expected_temperature
-             FROM temperatures
-             LEFT JOIN labels ON labels.key = temperatures.key
-             WHERE temperatures.key = ?
-             ORDER BY temperatures.timestamp DESC LIMIT 1
-             "#,
+              current_state.key,
+              COALESCE(labels.label, current_state.key),
+              current_state.temperature_timestamp,
+              current_state.temperature,
+              current_state.expected_temperature
+            FROM current_state
+            LEFT JOIN labels ON labels.key = current_state.key
+            WHERE current_state.key = ?1 AND current_state.temperature IS NOT NULL
+            "#,
             params![key],
             |row| {
-                let mut result = HashMap::from([
-                    ("key", row.get::<_, String>(0)?),
-                    ("label", row.get::<_, String>(1)?),
-                    (
-                        "timestamp",
-                        NaiveDateTime::parse_from_str(
-                            &row.get::<_, String>(2)?,
-                            "%Y-%m-%d %H:%M:%S",
-                        )
-                        .map(|dt| dt.and_utc().to_string())
-                        .unwrap(),
-                    ),
-                    ("temperature", row.get::<_, f32>(3)?.to_string()),
-                ]);
-                if let Some(expected_temp) = row.get::<_, Option<f32>>(4)? {
-                    result.insert("expected_temperature", expected_temp.to_string());
-                }
-                Ok(result)
+                Ok(TemperatureReading {
+                    key: row.get(0)?,
+                    label: row.get(1)?,
+                    timestamp: row_timestamp(row, 2)?,
+                    temperature: row.get(3)?,
+                    expected_temperature: row.get(4)?,
+                })
             },
         )
+        .optional()
     })
     .map_err(|e| {
         let err_msg = format!("Failed to get latest temperature for key {}: {}", key, e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
 
@@ -215,7 +176,7 @@ pub fn get_temperatures_since(
             since, key, e
         );
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
 
@@ -223,30 +184,57 @@ pub fn store_state(
     conn: &Arc<Mutex<Connection>>,
     key: &str,
     state: bool,
+    timestamp: DateTime<Utc>,
 ) -> Result<(), NeuroheatError> {
     db::with_locked_connection(conn, |conn| {
-        conn.execute(
-            "INSERT INTO states (key, state) VALUES (?1, ?2)",
-            params![key, state as i32],
-        )
-        .map(|_| ())
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO states (key, state, timestamp) VALUES (?1, ?2, ?3)",
+            params![
+                key,
+                state as i32,
+                timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+            ],
+        )?;
+        // the change time is kept while the same state is recorded again
+        tx.execute(
+            r#"
+            INSERT INTO current_state (key, state, state_timestamp, state_changed_at)
+            SELECT key, state, timestamp, timestamp
+            FROM states
+            WHERE id = last_insert_rowid()
+            ON CONFLICT (key) DO UPDATE SET
+              state_changed_at = CASE
+                WHEN current_state.state IS excluded.state THEN current_state.state_changed_at
+                ELSE excluded.state_changed_at
+              END,
+              state = excluded.state,
+              state_timestamp = excluded.state_timestamp
+            "#,
+            [],
+        )?;
+        tx.commit()
     })
     .map_err(|e| {
         let err_msg = format!("Failed to store state for key {}: {}", key, e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
     })
 }
 
+/// Returns the latest recorded state for each key together with the time
+/// it changed to that state. States are also recorded periodically without
+/// a change, so the timestamp is the one of the first record in the current
+/// run of equal states (maintained by `store_state`).
 pub fn get_valve_states_and_timestamps(
     conn: &Arc<Mutex<Connection>>,
 ) -> Result<HashMap<String, (bool, DateTime<Utc>)>, NeuroheatError> {
     db::with_locked_connection(conn, |conn| {
         let mut stmt = conn.prepare(
             r#"
-            SELECT key, state, MAX(timestamp) AS latest_timestamp
-            FROM states
-            GROUP BY key
+            SELECT key, state, state_changed_at
+            FROM current_state
+            WHERE state IS NOT NULL
             "#,
         )?;
 
@@ -279,6 +267,1381 @@ pub fn get_valve_states_and_timestamps(
     .map_err(|e| {
         let err_msg = format!("Failed to get valve states and timestamps: {}", e);
         log::error!("{}", err_msg);
-        NeuroheatError::DatabaseError(err_msg)
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn get_pi_state(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+) -> Result<Option<PiState>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.query_row(
+            r#"
+            SELECT integral, duty_cycle, cycle_start
+            FROM pi_controller_state
+            WHERE key = ?1
+            "#,
+            params![key],
+            |row| {
+                Ok(PiState {
+                    integral: row.get(0)?,
+                    duty_cycle: row.get(1)?,
+                    cycle_start: row_timestamp(row, 2)?,
+                })
+            },
+        )
+        .optional()
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get PI controller state for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn store_pi_state(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    state: &PiState,
+) -> Result<(), NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.execute(
+            r#"
+            INSERT INTO pi_controller_state (key, integral, duty_cycle, cycle_start)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (key) DO UPDATE SET
+              integral = excluded.integral,
+              duty_cycle = excluded.duty_cycle,
+              cycle_start = excluded.cycle_start
+            "#,
+            params![
+                key,
+                state.integral,
+                state.duty_cycle,
+                state.cycle_start.format("%Y-%m-%d %H:%M:%S").to_string(),
+            ],
+        )
+    })
+    .map(|_| ())
+    .map_err(|e| {
+        let err_msg = format!("Failed to store PI controller state for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn store_override(
+    conn: &Arc<Mutex<Connection>>,
+    temperature_override: &TemperatureOverride,
+) -> Result<(), NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.execute(
+            r#"
+            INSERT OR REPLACE INTO overrides (key, temperature, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4)
+            "#,
+            params![
+                temperature_override.key,
+                temperature_override.temperature,
+                temperature_override
+                    .created_at
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                temperature_override
+                    .expires_at
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+            ],
+        )
+        .map(|_| ())
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to store override for key {}: {}",
+            temperature_override.key, e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn get_active_overrides(
+    conn: &Arc<Mutex<Connection>>,
+    now: DateTime<Utc>,
+) -> Result<Vec<TemperatureOverride>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT key, temperature, created_at, expires_at
+            FROM overrides
+            WHERE expires_at > ?1
+            ORDER BY key
+            "#,
+        )?;
+
+        let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        let overrides = stmt
+            .query_map(params![timestamp], override_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(overrides)
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get active overrides: {}", e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
     })
 }
+
+pub fn get_active_override(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<TemperatureOverride>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        conn.query_row(
+            r#"
+            SELECT key, temperature, created_at, expires_at
+            FROM overrides
+            WHERE key = ?1 AND expires_at > ?2
+            "#,
+            params![key, timestamp],
+            override_from_row,
+        )
+        .optional()
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get override for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Removes the override for the given key. Returns whether an active
+/// override was cancelled.
+pub fn delete_override(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    now: DateTime<Utc>,
+) -> Result<bool, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        let deleted = conn.execute(
+            "DELETE FROM overrides WHERE key = ?1 AND expires_at > ?2",
+            params![key, timestamp],
+        )?;
+        conn.execute(
+            "DELETE FROM overrides WHERE expires_at <= ?1",
+            params![timestamp],
+        )?;
+
+        Ok(deleted > 0)
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to delete override for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+fn override_from_row(row: &rusqlite::Row) -> rusqlite::Result<TemperatureOverride> {
+    Ok(TemperatureOverride {
+        key: row.get(0)?,
+        temperature: row.get(1)?,
+        created_at: row_timestamp(row, 2)?,
+        expires_at: row_timestamp(row, 3)?,
+    })
+}
+
+/// Parses a timestamp stored in the `YYYY-MM-DD HH:MM:SS` format (in UTC).
+fn row_timestamp(row: &rusqlite::Row, index: usize) -> rusqlite::Result<DateTime<Utc>> {
+    let timestamp = row.get::<_, String>(index)?;
+    NaiveDateTime::parse_from_str(&timestamp, "%Y-%m-%d %H:%M:%S")
+        .map(|dt| dt.and_utc())
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+        })
+}
+
+pub fn get_label(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+) -> Result<Option<String>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.query_row(
+            "SELECT label FROM labels WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get label for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn get_temperature_history(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    query: &HistoryQuery,
+) -> Result<Vec<TemperatureBucket>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let mut stmt = conn.prepare(
+            r#"
+            WITH samples (timestamp, min, max, total, samples, expected_total, expected_samples) AS (
+              SELECT
+                timestamp,
+                temperature,
+                temperature,
+                temperature,
+                1,
+                expected_temperature,
+                expected_temperature IS NOT NULL
+              FROM temperatures
+              WHERE key = ?1 AND timestamp >= ?2 AND timestamp < ?3
+              UNION ALL
+              SELECT
+                timestamp,
+                min,
+                max,
+                avg * samples,
+                samples,
+                expected_temperature * expected_samples,
+                expected_samples
+              FROM temperatures_hourly
+              WHERE key = ?1 AND timestamp >= ?2 AND timestamp < ?3
+              UNION ALL
+              SELECT
+                timestamp,
+                min,
+                max,
+                avg * samples,
+                samples,
+                expected_temperature * expected_samples,
+                expected_samples
+              FROM temperatures_daily
+              WHERE key = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            )
+            SELECT
+              (CAST(strftime('%s', timestamp) AS INTEGER) / ?4) * ?4 AS bucket,
+              MIN(min),
+              SUM(total) / SUM(samples),
+              MAX(max),
+              SUM(expected_total) / NULLIF(SUM(expected_samples), 0),
+              SUM(samples)
+            FROM samples
+            GROUP BY bucket
+            ORDER BY bucket
+            "#,
+        )?;
+
+        let buckets = stmt
+            .query_map(
+                params![
+                    key,
+                    query.from.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    query.to.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    query.resolution.num_seconds(),
+                ],
+                |row| {
+                    Ok(TemperatureBucket {
+                        timestamp: bucket_timestamp(row, 0)?,
+                        min: row.get(1)?,
+                        avg: row.get::<_, f64>(2)? as f32,
+                        max: row.get(3)?,
+                        expected_temperature: row.get::<_, Option<f64>>(4)?.map(|t| t as f32),
+                        samples: row.get(5)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(buckets)
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get temperature history for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn get_state_history(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    query: &HistoryQuery,
+) -> Result<Vec<StateBucket>, NeuroheatError> {
+    let periods = db::with_locked_connection(conn, |conn| {
+        let format = |time: DateTime<Utc>| time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let mut periods = Vec::new();
+
+        // Aggregates (older than raw states) overlapping the range.
+        for (table, length) in [
+            ("states_daily", Duration::days(1)),
+            ("states_hourly", Duration::hours(1)),
+        ] {
+            let mut stmt = conn.prepare(&format!(
+                r#"
+                SELECT timestamp, last_state, enabled_seconds, samples
+                FROM {}
+                WHERE key = ?1 AND timestamp > ?2 AND timestamp < ?3
+                ORDER BY timestamp
+                "#,
+                table
+            ))?;
+            let rows = stmt.query_map(
+                params![key, format(query.from - length), format(query.to)],
+                |row| {
+                    let start = row_timestamp(row, 0)?;
+                    let samples: u32 = row.get(3)?;
+                    Ok(StatePeriod {
+                        start,
+                        end: start + length,
+                        enabled_ratio: row.get::<_, f64>(2)? / length.num_seconds() as f64,
+                        last_state: row.get::<_, i32>(1)? != 0,
+                        samples,
+                    })
+                },
+            )?;
+            for period in rows {
+                periods.push(period?);
+            }
+        }
+
+        // Each raw state lasts until the next one. States recorded before
+        // the range carry into it.
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT timestamp, state
+            FROM states
+            WHERE key = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            ORDER BY timestamp, id
+            "#,
+        )?;
+        let states = stmt
+            .query_map(
+                params![
+                    key,
+                    format(query.from - Duration::seconds(MAX_STATE_SECONDS)),
+                    format(query.to)
+                ],
+                |row| Ok((row_timestamp(row, 0)?, row.get::<_, i32>(1)? != 0)),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, (start, state)) in states.iter().enumerate() {
+            let mut end = (*start + Duration::seconds(MAX_STATE_SECONDS)).min(query.to);
+            if let Some((next, _)) = states.get(index + 1) {
+                end = end.min(*next);
+            }
+            periods.push(StatePeriod {
+                start: *start,
+                end,
+                enabled_ratio: if *state { 1.0 } else { 0.0 },
+                last_state: *state,
+                samples: 1,
+            });
+        }
+
+        periods.sort_by_key(|period| period.start);
+        Ok(periods)
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get state history for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })?;
+
+    Ok(history::state_buckets(&periods, query))
+}
+
+/// Returns the temperatures recorded in the given period, oldest first,
+/// with the time each of them stands for. Raw readings stand for an
+/// instant; periods that were downsampled are read from the hourly and
+/// daily aggregates, with each row standing for its whole bucket at its
+/// average temperature.
+pub fn get_recorded_temperatures(
+    conn: &Arc<Mutex<Connection>>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<(TemperatureReading, Duration)>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let format = |time: DateTime<Utc>| time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let mut readings = Vec::new();
+
+        for (table, temperature, length) in [
+            ("temperatures_daily", "avg", Duration::days(1)),
+            ("temperatures_hourly", "avg", Duration::hours(1)),
+            ("temperatures", "temperature", Duration::zero()),
+        ] {
+            let mut stmt = conn.prepare(&format!(
+                r#"
+                SELECT
+                  {table}.key,
+                  COALESCE(labels.label, {table}.key),
+                  {table}.timestamp,
+                  {table}.{temperature},
+                  {table}.expected_temperature
+                FROM {table}
+                LEFT JOIN labels ON labels.key = {table}.key
+                WHERE {table}.timestamp > ?1 AND {table}.timestamp < ?2
+                ORDER BY {table}.timestamp
+                "#,
+            ))?;
+            // Buckets starting before the period still cover its start.
+            let since = from - length - Duration::seconds(1);
+            let rows = stmt.query_map(params![format(since), format(to)], |row| {
+                Ok((
+                    TemperatureReading {
+                        key: row.get(0)?,
+                        label: row.get(1)?,
+                        timestamp: row_timestamp(row, 2)?,
+                        temperature: row.get(3)?,
+                        expected_temperature: row.get(4)?,
+                    },
+                    length,
+                ))
+            })?;
+            for reading in rows {
+                readings.push(reading?);
+            }
+        }
+
+        // Stable, so raw readings keep the order they were recorded in.
+        readings.sort_by_key(|(reading, _)| reading.timestamp);
+        Ok(readings)
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to get recorded temperatures from {} to {}: {}",
+            from, to, e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Returns the relay states (key, timestamp and state) recorded in the
+/// given period, oldest first. The last state of each key before the period
+/// is included, so the state at its start is known. Periods that were
+/// downsampled are read from the hourly and daily aggregates: the relay is
+/// taken to be on for the first `enabled_seconds` of each bucket.
+pub fn get_recorded_states(
+    conn: &Arc<Mutex<Connection>>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<(String, DateTime<Utc>, bool)>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT key, timestamp, state
+            FROM states
+            WHERE (timestamp >= ?1 AND timestamp < ?2)
+              OR id IN (
+                SELECT id
+                FROM (
+                  SELECT
+                    id,
+                    ROW_NUMBER() OVER (PARTITION BY key ORDER BY timestamp DESC, id DESC) AS position
+                  FROM states
+                  WHERE timestamp < ?1
+                )
+                WHERE position = 1
+              )
+            ORDER BY timestamp, id
+            "#,
+        )?;
+
+        let mut states = stmt
+            .query_map(
+                params![
+                    from.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    to.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row_timestamp(row, 1)?,
+                        row.get::<_, i32>(2)? != 0,
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<(String, DateTime<Utc>, bool)>, _>>()?;
+
+        for (table, length) in [
+            ("states_daily", Duration::days(1)),
+            ("states_hourly", Duration::hours(1)),
+        ] {
+            let mut stmt = conn.prepare(&format!(
+                r#"
+                SELECT key, timestamp, enabled_seconds
+                FROM {}
+                WHERE timestamp > ?1 AND timestamp < ?2
+                ORDER BY timestamp
+                "#,
+                table
+            ))?;
+            let rows = stmt.query_map(
+                params![
+                    (from - length).format("%Y-%m-%d %H:%M:%S").to_string(),
+                    to.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row_timestamp(row, 1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )?;
+            for row in rows {
+                let (key, start, enabled_seconds) = row?;
+                let enabled = Duration::seconds(enabled_seconds).min(length);
+                states.push((key.clone(), start, enabled > Duration::zero()));
+                if enabled > Duration::zero() && enabled < length {
+                    states.push((key, start + enabled, false));
+                }
+            }
+        }
+
+        // Stable, so raw states keep the order they were recorded in.
+        states.sort_by_key(|(_, timestamp, _)| *timestamp);
+        Ok(states)
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to get recorded states from {} to {}: {}",
+            from, to, e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+fn bucket_timestamp(row: &rusqlite::Row, index: usize) -> rusqlite::Result<DateTime<Utc>> {
+    let seconds = row.get::<_, i64>(index)?;
+    DateTime::from_timestamp(seconds, 0)
+        .ok_or(rusqlite::Error::IntegralValueOutOfRange(index, seconds))
+}
+
+pub fn store_stove_decision(
+    conn: &Arc<Mutex<Connection>>,
+    decision: StoveDecision,
+    reason: &str,
+    pipe_temperature: Option<f32>,
+    open_area: f32,
+    timestamp: DateTime<Utc>,
+) -> Result<(), NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.execute(
+            r#"
+            INSERT INTO stove_decisions (decision, reason, pipe_temperature, open_area, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+            params![
+                decision.as_str(),
+                reason,
+                pipe_temperature,
+                open_area,
+                timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+            ],
+        )
+        .map(|_| ())
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to store stove decision {}: {}",
+            decision.as_str(),
+            e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn has_stove_decision_since(
+    conn: &Arc<Mutex<Connection>>,
+    decision: StoveDecision,
+    since: DateTime<Utc>,
+) -> Result<bool, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.query_row(
+            r#"
+            SELECT EXISTS (
+              SELECT 1
+              FROM stove_decisions
+              WHERE decision = ?1 AND timestamp >= ?2
+            )
+            "#,
+            params![
+                decision.as_str(),
+                since.format("%Y-%m-%d %H:%M:%S").to_string()
+            ],
+            |row| row.get(0),
+        )
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get stove decisions since {}: {}", since, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Returns the latest stored temperature for the key with its timestamp.
+pub fn get_latest_reading(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+) -> Result<Option<(f32, DateTime<Utc>)>, NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.query_row(
+            r#"
+            SELECT temperature, temperature_timestamp
+            FROM current_state
+            WHERE key = ?1 AND temperature IS NOT NULL
+            "#,
+            params![key],
+            |row| Ok((row.get(0)?, row_timestamp(row, 1)?)),
+        )
+        .optional()
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to get latest reading for key {}: {}", key, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+pub fn store_rejected_temperature(
+    conn: &Arc<Mutex<Connection>>,
+    key: &str,
+    temperature: f32,
+    reason: &str,
+    timestamp: DateTime<Utc>,
+) -> Result<(), NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.execute(
+            r#"
+            INSERT INTO rejected_temperatures (key, temperature, reason, timestamp)
+            VALUES (?1, ?2, ?3, ?4)
+            "#,
+            params![
+                key,
+                temperature,
+                reason,
+                timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+            ],
+        )
+        .map(|_| ())
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to store rejected temperature for key {}: {}",
+            key, e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Checks that the database accepts writes by taking (and releasing) the
+/// write lock without changing anything.
+pub fn check_writable(conn: &Arc<Mutex<Connection>>) -> Result<(), NeuroheatError> {
+    db::with_locked_connection(conn, |conn| {
+        conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+    })
+    .map_err(|e| {
+        let err_msg = format!("Database is not writable: {}", e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// The number of rows moved by [`downsample`].
+#[derive(Debug, Default)]
+pub struct DownsampleSummary {
+    pub raw_temperatures: usize,
+    pub raw_states: usize,
+    pub hourly_temperatures: usize,
+    pub hourly_states: usize,
+    pub rejected_temperatures: usize,
+}
+
+/// Merges aggregated temperatures into an existing aggregate row.
+const MERGE_TEMPERATURES: &str = r#"
+    ON CONFLICT (key, timestamp) DO UPDATE SET
+      min = MIN(min, excluded.min),
+      avg = (avg * samples + excluded.avg * excluded.samples) / (samples + excluded.samples),
+      max = MAX(max, excluded.max),
+      expected_temperature = CASE
+        WHEN expected_samples + excluded.expected_samples = 0 THEN NULL
+        ELSE (
+          COALESCE(expected_temperature * expected_samples, 0)
+          + COALESCE(excluded.expected_temperature * excluded.expected_samples, 0)
+        ) / (expected_samples + excluded.expected_samples)
+      END,
+      expected_samples = expected_samples + excluded.expected_samples,
+      samples = samples + excluded.samples
+"#;
+
+/// Merges aggregated states into an existing aggregate row.
+const MERGE_STATES: &str = r#"
+    ON CONFLICT (key, timestamp) DO UPDATE SET
+      last_state = excluded.last_state,
+      enabled_samples = enabled_samples + excluded.enabled_samples,
+      samples = samples + excluded.samples,
+      enabled_seconds = enabled_seconds + excluded.enabled_seconds
+"#;
+
+/// How many days of data one downsampling transaction covers at most, so a
+/// large backlog (e.g., years of readings) does not hold the database for
+/// long.
+const DOWNSAMPLE_BATCH_DAYS: i64 = 1;
+
+/// Rolls raw readings recorded before `raw_before` into hourly aggregates
+/// and hourly aggregates from before `hourly_before` into daily ones, then
+/// deletes the rolled up rows. Rejected readings older than `raw_before`
+/// are deleted as well. The data is processed from the oldest day on, one
+/// transaction per day, and the database is unlocked between them.
+pub fn downsample(
+    conn: &Arc<Mutex<Connection>>,
+    raw_before: DateTime<Utc>,
+    hourly_before: DateTime<Utc>,
+) -> Result<DownsampleSummary, NeuroheatError> {
+    let mut summary = DownsampleSummary::default();
+
+    while let Some(oldest) = oldest_timestamp(conn, &["temperatures", "states"], raw_before)? {
+        let before = batch_end(oldest).min(raw_before);
+        let (temperatures, states) = downsample_batch(conn, before, downsample_raw)?;
+        summary.raw_temperatures += temperatures;
+        summary.raw_states += states;
+    }
+
+    while let Some(oldest) = oldest_timestamp(
+        conn,
+        &["temperatures_hourly", "states_hourly"],
+        hourly_before,
+    )? {
+        let before = batch_end(oldest).min(hourly_before);
+        let (temperatures, states) = downsample_batch(conn, before, downsample_hourly)?;
+        summary.hourly_temperatures += temperatures;
+        summary.hourly_states += states;
+    }
+
+    summary.rejected_temperatures = db::with_locked_connection(conn, |conn| {
+        conn.execute(
+            "DELETE FROM rejected_temperatures WHERE timestamp < ?1",
+            params![raw_before.format("%Y-%m-%d %H:%M:%S").to_string()],
+        )
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to delete old rejected temperatures: {}", e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })?;
+
+    Ok(summary)
+}
+
+/// Returns the end of the batch starting with a row at `oldest`: the start
+/// of the next day.
+fn batch_end(oldest: DateTime<Utc>) -> DateTime<Utc> {
+    let day = oldest.timestamp().div_euclid(86400) * 86400;
+    DateTime::from_timestamp(day, 0).unwrap_or(oldest) + Duration::days(DOWNSAMPLE_BATCH_DAYS)
+}
+
+/// Returns the oldest timestamp before `before` in any of the tables.
+fn oldest_timestamp(
+    conn: &Arc<Mutex<Connection>>,
+    tables: &[&str],
+    before: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, NeuroheatError> {
+    let query = tables
+        .iter()
+        .map(|table| {
+            format!(
+                "SELECT MIN(timestamp) AS timestamp FROM {} WHERE timestamp < ?1",
+                table
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+
+    db::with_locked_connection(conn, |conn| {
+        conn.query_row(
+            &format!("SELECT MIN(timestamp) FROM ({})", query),
+            params![before.format("%Y-%m-%d %H:%M:%S").to_string()],
+            |row| match row.get::<_, Option<String>>(0)? {
+                Some(_) => row_timestamp(row, 0).map(Some),
+                None => Ok(None),
+            },
+        )
+    })
+    .map_err(|e| {
+        let err_msg = format!(
+            "Failed to find the oldest row of {}: {}",
+            tables.join(", "),
+            e
+        );
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Runs one downsampling step for the rows before `before` in its own
+/// transaction and returns the numbers of rolled up temperatures and states.
+fn downsample_batch(
+    conn: &Arc<Mutex<Connection>>,
+    before: DateTime<Utc>,
+    step: fn(&Connection, &str) -> rusqlite::Result<(usize, usize)>,
+) -> Result<(usize, usize), NeuroheatError> {
+    let before = before.format("%Y-%m-%d %H:%M:%S").to_string();
+
+    db::with_locked_connection(conn, |conn| {
+        let tx = conn.unchecked_transaction()?;
+        let rows = step(&tx, &before)?;
+        tx.commit()?;
+        Ok(rows)
+    })
+    .map_err(|e| {
+        let err_msg = format!("Failed to downsample data from before {}: {}", before, e);
+        log::error!("{}", err_msg);
+        NeuroheatError::database(err_msg, &*e)
+    })
+}
+
+/// Rolls the raw readings and states from before `raw_before` into hourly
+/// aggregates.
+fn downsample_raw(tx: &Connection, raw_before: &str) -> rusqlite::Result<(usize, usize)> {
+    tx.execute(
+        &format!(
+            r#"
+            INSERT INTO temperatures_hourly
+              (key, timestamp, min, avg, max, expected_temperature, expected_samples, samples)
+            SELECT
+              key,
+              strftime('%Y-%m-%d %H:00:00', timestamp) AS hour,
+              MIN(temperature),
+              AVG(temperature),
+              MAX(temperature),
+              AVG(expected_temperature),
+              COUNT(expected_temperature),
+              COUNT(*)
+            FROM temperatures
+            WHERE timestamp < ?1
+            GROUP BY key, hour
+            {}
+            "#,
+            MERGE_TEMPERATURES
+        ),
+        params![raw_before],
+    )?;
+    let temperatures = tx.execute(
+        "DELETE FROM temperatures WHERE timestamp < ?1",
+        params![raw_before],
+    )?;
+
+    // Each state lasts until the next one of the same key (capped to
+    // ignore gaps such as downtime). Its duration is split between the
+    // hours it overlaps; a capped state overlaps two hours at most.
+    tx.execute(
+        &format!(
+            r#"
+            WITH segments AS (
+              SELECT
+                key,
+                state,
+                id,
+                CAST(strftime('%s', timestamp) AS INTEGER) AS start,
+                MIN(
+                  COALESCE(
+                    CAST(strftime('%s', LEAD(timestamp) OVER (
+                      PARTITION BY key ORDER BY timestamp, id
+                    )) AS INTEGER),
+                    CAST(strftime('%s', ?1) AS INTEGER)
+                  ),
+                  CAST(strftime('%s', timestamp) AS INTEGER) + {max_seconds},
+                  CAST(strftime('%s', ?1) AS INTEGER)
+                ) AS end
+              FROM states
+              WHERE timestamp < ?1
+            ),
+            pieces AS (
+              SELECT key, state, id, start, start / 3600 * 3600 AS hour,
+                MIN(end, start / 3600 * 3600 + 3600) - start AS seconds
+              FROM segments
+              UNION ALL
+              SELECT key, state, id, start / 3600 * 3600 + 3600, start / 3600 * 3600 + 3600,
+                end - (start / 3600 * 3600 + 3600)
+              FROM segments
+              WHERE end > start / 3600 * 3600 + 3600
+            ),
+            hours AS (
+              SELECT
+                key,
+                hour,
+                SUM(CASE WHEN state != 0 THEN seconds ELSE 0 END) AS enabled_seconds
+              FROM pieces
+              GROUP BY key, hour
+            ),
+            last_states AS (
+              SELECT key, hour, state
+              FROM (
+                SELECT
+                  key,
+                  hour,
+                  state,
+                  ROW_NUMBER() OVER (
+                    PARTITION BY key, hour ORDER BY start DESC, id DESC
+                  ) AS position
+                FROM pieces
+              )
+              WHERE position = 1
+            ),
+            counts AS (
+              SELECT key, start / 3600 * 3600 AS hour, SUM(state != 0) AS enabled_samples, COUNT(*) AS samples
+              FROM segments
+              GROUP BY key, hour
+            )
+            INSERT INTO states_hourly
+              (key, timestamp, last_state, enabled_samples, samples, enabled_seconds)
+            SELECT
+              hours.key,
+              strftime('%Y-%m-%d %H:%M:%S', hours.hour, 'unixepoch'),
+              last_states.state,
+              COALESCE(counts.enabled_samples, 0),
+              COALESCE(counts.samples, 0),
+              hours.enabled_seconds
+            FROM hours
+            JOIN last_states ON last_states.key = hours.key AND last_states.hour = hours.hour
+            LEFT JOIN counts ON counts.key = hours.key AND counts.hour = hours.hour
+            WHERE true
+            {merge}
+            "#,
+            max_seconds = MAX_STATE_SECONDS,
+            merge = MERGE_STATES
+        ),
+        params![raw_before],
+    )?;
+    // The state in effect at the cutoff stays as a raw record, so the
+    // time after the cutoff is still covered by it.
+    tx.execute(
+        r#"
+        INSERT INTO states (key, state, timestamp)
+        SELECT key, state, ?1
+        FROM (
+          SELECT
+            key,
+            state,
+            timestamp,
+            ROW_NUMBER() OVER (PARTITION BY key ORDER BY timestamp DESC, id DESC) AS position
+          FROM states
+          WHERE timestamp < ?1
+        ) AS latest
+        WHERE position = 1
+          AND timestamp > datetime(?1, ?2)
+          AND NOT EXISTS (
+            SELECT 1 FROM states AS at_cutoff
+            WHERE at_cutoff.key = latest.key AND at_cutoff.timestamp = ?1
+          )
+        "#,
+        params![raw_before, format!("-{} seconds", MAX_STATE_SECONDS)],
+    )?;
+    let states = tx.execute(
+        "DELETE FROM states WHERE timestamp < ?1",
+        params![raw_before],
+    )?;
+
+    Ok((temperatures, states))
+}
+
+/// Rolls the hourly aggregates from before `hourly_before` into daily ones.
+fn downsample_hourly(tx: &Connection, hourly_before: &str) -> rusqlite::Result<(usize, usize)> {
+    tx.execute(
+        &format!(
+            r#"
+            INSERT INTO temperatures_daily
+              (key, timestamp, min, avg, max, expected_temperature, expected_samples, samples)
+            SELECT
+              key,
+              strftime('%Y-%m-%d 00:00:00', timestamp) AS day,
+              MIN(min),
+              SUM(avg * samples) / SUM(samples),
+              MAX(max),
+              SUM(expected_temperature * expected_samples) / NULLIF(SUM(expected_samples), 0),
+              SUM(expected_samples),
+              SUM(samples)
+            FROM temperatures_hourly
+            WHERE timestamp < ?1
+            GROUP BY key, day
+            {}
+            "#,
+            MERGE_TEMPERATURES
+        ),
+        params![hourly_before],
+    )?;
+    let temperatures = tx.execute(
+        "DELETE FROM temperatures_hourly WHERE timestamp < ?1",
+        params![hourly_before],
+    )?;
+
+    tx.execute(
+        &format!(
+            r#"
+            WITH ranked AS (
+              SELECT
+                *,
+                strftime('%Y-%m-%d 00:00:00', timestamp) AS day,
+                FIRST_VALUE(last_state) OVER (
+                  PARTITION BY key, strftime('%Y-%m-%d', timestamp)
+                  ORDER BY timestamp DESC
+                ) AS latest_state
+              FROM states_hourly
+              WHERE timestamp < ?1
+            )
+            INSERT INTO states_daily
+              (key, timestamp, last_state, enabled_samples, samples, enabled_seconds)
+            SELECT
+              key,
+              day,
+              MAX(latest_state),
+              SUM(enabled_samples),
+              SUM(samples),
+              SUM(enabled_seconds)
+            FROM ranked
+            GROUP BY key, day
+            {}
+            "#,
+            MERGE_STATES
+        ),
+        params![hourly_before],
+    )?;
+    let states = tx.execute(
+        "DELETE FROM states_hourly WHERE timestamp < ?1",
+        params![hourly_before],
+    )?;
+
+    Ok((temperatures, states))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::start;
+    use chrono::Duration;
+
+    fn setup(keys: &[&str]) -> Arc<Mutex<Connection>> {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        db::migrate(&conn).unwrap();
+        db::with_locked_connection(&conn, |conn| {
+            for key in keys {
+                conn.execute(
+                    "INSERT INTO labels (key, label) VALUES (?1, ?1)",
+                    params![key],
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn current_state_has_the_latest_valve_state_of_each_room() {
+        let conn = setup(&["office", "kitchen", "stove"]);
+        let now = start();
+        store_temperature(&conn, "office", 20.5, Some(21.0), now).unwrap();
+        store_temperature(&conn, "kitchen", 22.0, Some(21.0), now).unwrap();
+        store_state(&conn, "office", true, now).unwrap();
+        store_state(&conn, "kitchen", false, now).unwrap();
+        store_state(&conn, "stove", true, now).unwrap();
+
+        let state = get_current_state(&conn).unwrap();
+
+        assert_eq!(state["office"].heating_enabled, Some(true));
+        assert_eq!(state["office"].temperature, Some(20.5));
+        assert_eq!(state["kitchen"].heating_enabled, Some(false));
+        assert_eq!(state["kitchen"].temperature, Some(22.0));
+        assert_eq!(state["stove"].heating_enabled, Some(true));
+        assert_eq!(state["stove"].temperature, None);
+    }
+
+    #[test]
+    fn latest_write_wins_within_the_same_second() {
+        let conn = setup(&["office"]);
+        let now = start();
+        store_temperature(&conn, "office", 20.0, None, now).unwrap();
+        store_temperature(&conn, "office", 19.5, Some(21.0), now).unwrap();
+        store_state(&conn, "office", true, now).unwrap();
+        store_state(&conn, "office", false, now).unwrap();
+
+        let reading = get_latest_temperature(&conn, "office").unwrap().unwrap();
+        assert_eq!(reading.temperature, 19.5);
+        assert_eq!(reading.expected_temperature, Some(21.0));
+        assert_eq!(
+            get_latest_reading(&conn, "office").unwrap(),
+            Some((19.5, now))
+        );
+
+        let valve_states = get_valve_states_and_timestamps(&conn).unwrap();
+        assert_eq!(valve_states["office"], (false, now));
+    }
+
+    #[test]
+    fn state_change_time_is_kept_while_the_state_repeats() {
+        let conn = setup(&["office"]);
+        let changed_at = start();
+        store_state(&conn, "office", true, changed_at).unwrap();
+
+        store_state(&conn, "office", true, changed_at + Duration::minutes(15)).unwrap();
+        assert_eq!(
+            get_valve_states_and_timestamps(&conn).unwrap()["office"],
+            (true, changed_at)
+        );
+
+        let turned_off_at = changed_at + Duration::minutes(30);
+        store_state(&conn, "office", false, turned_off_at).unwrap();
+        assert_eq!(
+            get_valve_states_and_timestamps(&conn).unwrap()["office"],
+            (false, turned_off_at)
+        );
+    }
+
+    #[test]
+    fn keys_without_readings_have_no_current_state() {
+        let conn = setup(&["office", "kitchen"]);
+        store_temperature(&conn, "office", 20.0, None, start()).unwrap();
+
+        let state = get_current_state(&conn).unwrap();
+
+        assert!(state.contains_key("office"));
+        assert!(!state.contains_key("kitchen"));
+        assert!(get_latest_temperature(&conn, "kitchen").unwrap().is_none());
+        assert!(get_valve_states_and_timestamps(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overrides_expire() {
+        let conn = setup(&["office", "kitchen"]);
+        let now = start();
+        for (key, minutes) in [("office", 60), ("kitchen", 30)] {
+            let temperature_override = TemperatureOverride {
+                key: key.to_string(),
+                temperature: 23.0,
+                created_at: now,
+                expires_at: now + Duration::minutes(minutes),
+            };
+            store_override(&conn, &temperature_override).unwrap();
+        }
+        let active = |minutes| {
+            get_active_overrides(&conn, now + Duration::minutes(minutes))
+                .unwrap()
+                .into_iter()
+                .map(|temperature_override| temperature_override.key)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(active(29), vec!["kitchen", "office"]);
+        assert_eq!(active(30), vec!["office"]);
+        assert!(
+            get_active_override(&conn, "office", now + Duration::seconds(3599))
+                .unwrap()
+                .is_some()
+        );
+        let expired_at = now + Duration::minutes(60);
+        assert!(get_active_override(&conn, "office", expired_at)
+            .unwrap()
+            .is_none());
+        assert!(active(60).is_empty());
+        assert!(!delete_override(&conn, "office", expired_at).unwrap());
+    }
+
+    fn ratios(buckets: &[StateBucket]) -> Vec<f32> {
+        buckets.iter().map(|bucket| bucket.enabled_ratio).collect()
+    }
+
+    #[test]
+    fn state_history_is_weighted_by_time() {
+        let conn = setup(&["office"]);
+        let at = |minutes| start() + Duration::minutes(minutes);
+        // On since before the range; off for the second half of the first
+        // half hour, then on again with a repeated record.
+        store_state(&conn, "office", true, at(-10)).unwrap();
+        store_state(&conn, "office", false, at(15)).unwrap();
+        store_state(&conn, "office", true, at(30)).unwrap();
+        store_state(&conn, "office", true, at(45)).unwrap();
+
+        let query = HistoryQuery {
+            from: start(),
+            to: at(180),
+            resolution: Duration::minutes(30),
+        };
+        let buckets = get_state_history(&conn, "office", &query).unwrap();
+
+        // The last record holds for an hour; later buckets have no state.
+        assert_eq!(buckets.len(), 6);
+        assert_eq!(ratios(&buckets), vec![0.5, 1.0, 1.0, 0.5, 0.0, 0.0]);
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|bucket| bucket.samples)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 0, 0, 0, 0]
+        );
+        assert!(!buckets[0].heating_enabled);
+        assert!(buckets[3].heating_enabled);
+        assert!(!buckets[4].heating_enabled);
+    }
+
+    #[test]
+    fn downsamples_a_backlog_one_day_at_a_time() {
+        let conn = setup(&["office"]);
+        let midnight = start() - Duration::hours(12);
+        for day in [-2, -1, 0] {
+            let timestamp = midnight + Duration::days(day) + Duration::hours(1);
+            store_temperature(&conn, "office", 20.0, None, timestamp).unwrap();
+        }
+        // On across the end of the second batch.
+        store_state(&conn, "office", true, midnight - Duration::minutes(30)).unwrap();
+        store_state(&conn, "office", false, midnight + Duration::minutes(30)).unwrap();
+
+        let summary = downsample(
+            &conn,
+            midnight + Duration::days(1),
+            midnight - Duration::days(10),
+        )
+        .unwrap();
+
+        assert_eq!(summary.raw_temperatures, 3);
+        let hourly = HistoryQuery {
+            from: midnight - Duration::hours(1),
+            to: midnight + Duration::hours(1),
+            resolution: Duration::hours(1),
+        };
+        let buckets = get_state_history(&conn, "office", &hourly).unwrap();
+        assert_eq!(ratios(&buckets), vec![0.5, 0.5]);
+        assert!(buckets[0].heating_enabled);
+        assert!(!buckets[1].heating_enabled);
+        let remaining: i64 = db::with_locked_connection(&conn, |conn| {
+            conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM temperatures) + (SELECT COUNT(*) FROM states)",
+                [],
+                |row| row.get(0),
+            )
+        })
+        .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn downsampled_states_keep_their_on_time() {
+        let conn = setup(&["office"]);
+        let day = start() - Duration::hours(12);
+        let at = |minutes| day + Duration::minutes(minutes);
+        store_temperature(&conn, "office", 20.0, Some(21.0), at(-120)).unwrap();
+        store_temperature(&conn, "office", 22.0, Some(21.0), at(-90)).unwrap();
+        store_state(&conn, "office", true, at(-120)).unwrap();
+        store_state(&conn, "office", false, at(-75)).unwrap();
+        // Spans the hour boundary and the cutoff.
+        store_state(&conn, "office", true, at(-30)).unwrap();
+        store_state(&conn, "office", false, at(20)).unwrap();
+
+        let hourly = HistoryQuery {
+            from: at(-120),
+            to: at(60),
+            resolution: Duration::hours(1),
+        };
+        let before = ratios(&get_state_history(&conn, "office", &hourly).unwrap());
+        assert_eq!(before, vec![0.75, 0.5, 1.0 / 3.0]);
+
+        let summary = downsample(&conn, day, day - Duration::days(1)).unwrap();
+        assert_eq!(summary.raw_temperatures, 2);
+        assert_eq!(summary.raw_states, 3);
+
+        let after = get_state_history(&conn, "office", &hourly).unwrap();
+        assert_eq!(ratios(&after), before);
+        assert!(after[1].heating_enabled);
+        assert!(!after[2].heating_enabled);
+        let temperatures = get_temperature_history(&conn, "office", &hourly).unwrap();
+        assert_eq!(temperatures.len(), 1);
+        assert_eq!(temperatures[0].avg, 21.0);
+        assert_eq!(temperatures[0].samples, 2);
+
+        let summary = downsample(&conn, day, day).unwrap();
+        assert_eq!(summary.hourly_temperatures, 1);
+        assert_eq!(summary.hourly_states, 2);
+
+        let daily = HistoryQuery {
+            from: day - Duration::days(1),
+            to: day + Duration::days(1),
+            resolution: Duration::days(1),
+        };
+        let buckets = get_state_history(&conn, "office", &daily).unwrap();
+        assert_eq!(ratios(&buckets), vec![4500.0 / 86400.0, 1200.0 / 86400.0]);
+        assert_eq!(buckets[0].samples, 3);
+        let temperatures = get_temperature_history(&conn, "office", &daily).unwrap();
+        assert_eq!(temperatures[0].avg, 21.0);
+        assert_eq!(temperatures[0].min, 20.0);
+    }
+    #[test]
+    fn history_is_continuous_across_tiers() {
+        let conn = setup(&["office"]);
+        let day = start() - Duration::hours(12);
+        // Readings every 30 minutes from the day before until 06:00, with
+        // the valve open in even hours.
+        for half_hour in -48..12 {
+            let at = day + Duration::minutes(30 * half_hour);
+            let temperature = if half_hour % 2 == 0 { 20.0 } else { 21.0 };
+            store_temperature(&conn, "office", temperature, Some(21.0), at).unwrap();
+            store_state(&conn, "office", half_hour.div_euclid(2) % 2 == 0, at).unwrap();
+        }
+        let queries = [
+            HistoryQuery {
+                from: day,
+                to: day + Duration::hours(6),
+                resolution: Duration::hours(1),
+            },
+            HistoryQuery {
+                from: day - Duration::days(1),
+                to: day + Duration::days(1),
+                resolution: Duration::days(1),
+            },
+        ];
+        let history = || {
+            queries
+                .iter()
+                .map(|query| {
+                    let temperatures = get_temperature_history(&conn, "office", query)
+                        .unwrap()
+                        .iter()
+                        .map(|bucket| (bucket.timestamp, bucket.samples, bucket.avg))
+                        .collect::<Vec<_>>();
+                    let states = ratios(&get_state_history(&conn, "office", query).unwrap());
+                    (temperatures, states)
+                })
+                .collect::<Vec<_>>()
+        };
+        let before = history();
+        assert_eq!(before[0].0.len(), 6);
+        assert!(before[0].0.iter().all(|(_, samples, _)| *samples == 2));
+        assert_eq!(before[0].1, vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(before[1].0[0].1, 48);
+        assert_eq!(before[1].0[1].1, 12);
+
+        // Raw until 03:10, hourly until midnight and daily before that.
+        let raw_before = day + Duration::minutes(190);
+        downsample(&conn, raw_before, day).unwrap();
+        assert_eq!(history(), before);
+
+        let summary = downsample(&conn, raw_before, day).unwrap();
+        assert_eq!(summary.raw_temperatures, 0);
+        assert_eq!(summary.raw_states, 0);
+        assert_eq!(summary.hourly_temperatures, 0);
+        assert_eq!(summary.hourly_states, 0);
+        assert_eq!(history(), before);
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+
+use crate::heating_configuration::HeatingConfiguration;
+
+/// How long a relay was on and how often it was turned on.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RelayReport {
+    pub on_minutes: f64,
+    pub starts: u32,
+    state: Option<bool>,
+}
+
+impl RelayReport {
+    fn record(&mut self, on: bool, duration: Duration) {
+        if on {
+            self.on_minutes += duration.num_seconds() as f64 / 60.0;
+            if self.state == Some(false) {
+                self.starts += 1;
+            }
+        }
+        self.state = Some(on);
+    }
+}
+
+/// The temperatures of a room and the time its valve was open.
+#[derive(Debug, Clone)]
+pub struct RoomReport {
+    pub key: String,
+    pub name: String,
+    pub average_temperature: Option<f32>,
+    pub min_temperature: Option<f32>,
+    pub max_temperature: Option<f32>,
+    /// The time the temperature was below the expected temperature minus
+    /// the hysteresis.
+    pub below_setpoint_minutes: f64,
+    pub valve: RelayReport,
+    hysteresis: f32,
+    weighted_temperature: f64,
+    sampled_minutes: f64,
+}
+
+/// Summarizes the temperatures and relay states of a house over a period
+/// (e.g., a simulated one).
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub rooms: Vec<RoomReport>,
+    pub stove: RelayReport,
+}
+
+impl Report {
+    pub fn new(config: &HeatingConfiguration, from: DateTime<Utc>) -> Self {
+        let rooms = config
+            .rooms
+            .iter()
+            .map(|room| RoomReport {
+                key: room.key.clone(),
+                name: room.name.clone(),
+                average_temperature: None,
+                min_temperature: None,
+                max_temperature: None,
+                below_setpoint_minutes: 0.0,
+                valve: RelayReport::default(),
+                hysteresis: config.controller.hysteresis(room),
+                weighted_temperature: 0.0,
+                sampled_minutes: 0.0,
+            })
+            .collect();
+
+        Report {
+            from,
+            to: from,
+            rooms,
+            stove: RelayReport::default(),
+        }
+    }
+
+    /// Records the temperature and the valve state of a room that held for
+    /// the given duration.
+    pub fn record_room(
+        &mut self,
+        key: &str,
+        temperature: f32,
+        expected_temperature: Option<f32>,
+        valve_on: bool,
+        duration: Duration,
+    ) {
+        let Some(room) = self.rooms.iter_mut().find(|room| room.key == key) else {
+            return;
+        };
+        let minutes = duration.num_seconds() as f64 / 60.0;
+
+        room.weighted_temperature += temperature as f64 * minutes;
+        room.sampled_minutes += minutes;
+        if room.sampled_minutes > 0.0 {
+            room.average_temperature =
+                Some((room.weighted_temperature / room.sampled_minutes) as f32);
+        }
+        room.min_temperature = Some(
+            room.min_temperature
+                .map_or(temperature, |t| t.min(temperature)),
+        );
+        room.max_temperature = Some(
+            room.max_temperature
+                .map_or(temperature, |t| t.max(temperature)),
+        );
+        if expected_temperature.is_some_and(|expected| temperature < expected - room.hysteresis) {
+            room.below_setpoint_minutes += minutes;
+        }
+        room.valve.record(valve_on, duration);
+    }
+
+    /// Records the stove state that held for the given duration.
+    pub fn record_stove(&mut self, on: bool, duration: Duration) {
+        self.stove.record(on, duration);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let temperature = |t: Option<f32>| t.map_or("-".to_string(), |t| format!("{:.1}", t));
+
+        writeln!(f, "From {} to {}", self.from, self.to)?;
+        writeln!(
+            f,
+            "{:<20} {:>7} {:>7} {:>7} {:>14} {:>13} {:>12}",
+            "Room", "Avg °C", "Min °C", "Max °C", "Below setpoint", "Valve on", "Valve starts"
+        )?;
+        for room in &self.rooms {
+            writeln!(
+                f,
+                "{:<20} {:>7} {:>7} {:>7} {:>10.0} min {:>9.0} min {:>12}",
+                room.name,
+                temperature(room.average_temperature),
+                temperature(room.min_temperature),
+                temperature(room.max_temperature),
+                room.below_setpoint_minutes,
+                room.valve.on_minutes,
+                room.valve.starts
+            )?;
+        }
+        write!(
+            f,
+            "Stove on: {:.0} min, starts: {}",
+            self.stove.on_minutes, self.stove.starts
+        )
+    }
+}
+
+/// What happened over a period next to what a candidate configuration would
+/// have done.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub actual: Report,
+    pub candidate: Report,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "From {} to {}", self.actual.from, self.actual.to)?;
+        writeln!(
+            f,
+            "{:<20} {:>23} {:>23} {:>23}",
+            "", "Below setpoint (min)", "Valve on (min)", "Valve starts"
+        )?;
+        writeln!(
+            f,
+            "{:<20} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+            "Room", "actual", "candidate", "actual", "candidate", "actual", "candidate"
+        )?;
+        for (actual, candidate) in self.actual.rooms.iter().zip(&self.candidate.rooms) {
+            writeln!(
+                f,
+                "{:<20} {:>11.0} {:>11.0} {:>11.0} {:>11.0} {:>11} {:>11}",
+                actual.name,
+                actual.below_setpoint_minutes,
+                candidate.below_setpoint_minutes,
+                actual.valve.on_minutes,
+                candidate.valve.on_minutes,
+                actual.valve.starts,
+                candidate.valve.starts
+            )?;
+        }
+        writeln!(
+            f,
+            "Stove on: {:.0} min actual, {:.0} min candidate",
+            self.actual.stove.on_minutes, self.candidate.stove.on_minutes
+        )?;
+        write!(
+            f,
+            "Stove starts: {} actual, {} candidate",
+            self.actual.stove.starts, self.candidate.stove.starts
+        )
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::{Duration, DurationRound, Utc};
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+use crate::error::NeuroheatError;
+use crate::heating_configuration::HeatingConfiguration;
+use crate::repo;
+
+/// Rolls readings older than the configured retention into hourly and
+/// daily aggregates. The cutoffs are aligned to whole hours (days) so that
+/// every aggregate covers a complete period.
+pub async fn apply_retention(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+) -> Result<(), NeuroheatError> {
+    let now = config.clock.now();
+    let retention = &config.retention;
+    let align = |cutoff: chrono::DateTime<Utc>, period: Duration| {
+        cutoff.duration_trunc(period).map_err(|e| {
+            NeuroheatError::ValidationError(format!("Invalid retention cutoff: {}", e))
+        })
+    };
+
+    let raw_before = align(
+        now - Duration::days(retention.raw_days as i64),
+        Duration::hours(1),
+    )?;
+    let hourly_before = align(
+        now - Duration::days(retention.hourly_days as i64),
+        Duration::days(1),
+    )?;
+
+    let summary = repo::downsample(&conn, raw_before, hourly_before)?;
+    log::info!(
+        "Downsampled {} temperatures and {} states from before {} and {} hourly temperatures and {} hourly states from before {}; deleted {} rejected temperatures",
+        summary.raw_temperatures,
+        summary.raw_states,
+        raw_before,
+        summary.hourly_temperatures,
+        summary.hourly_states,
+        hourly_before,
+        summary.rejected_temperatures
+    );
+
+    Ok(())
+}
@@ -1,49 +1,188 @@
 use crate::controller;
+use crate::error::NeuroheatError;
+use crate::events::Events;
 use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
 use crate::relay::read_relay_states;
+use crate::retention;
 use crate::temperature_sensor::read_temperatures;
 
+use chrono::{DateTime, Utc};
+use croner::errors::CronError;
+use croner::Cron;
 use rusqlite::Connection;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{RwLock, RwLockReadGuard};
 use tokio_cron_scheduler::{Job, JobScheduler};
 
-/// How often to update the relay states for valves based on. e.g.,
-/// the room temperatures.
-const VALVE_CONTROLLER_CRON: &str = "30 */2 * * * *";
+/// The names of the jobs as reported in metrics.
+pub const TEMPERATURE_JOB: &str = "temperature";
+pub const RELAY_JOB: &str = "relay";
+pub const VALVE_CONTROLLER_JOB: &str = "valve_controller";
+pub const STOVE_CONTROLLER_JOB: &str = "stove_controller";
+pub const RETENTION_JOB: &str = "retention";
 
-/// How often to update the stove state based on the open valve areas.
-const STOVE_CONTROLLER_CRON: &str = "0 */5 * * * *";
+/// The jobs reading sensors and switching relays every few minutes.
+const CONTROLLER_JOBS: [&str; 3] = [TEMPERATURE_JOB, VALVE_CONTROLLER_JOB, STOVE_CONTROLLER_JOB];
 
-/// How often to read the relay states. They are also stored
-/// when updating their state.
-const RELAY_CRON: &str = "45 */15 * * * *";
+/// Returns the names of the jobs with their cron expressions.
+pub fn jobs(config: &HeatingConfiguration) -> [(&'static str, &str); 5] {
+    let scheduler = &config.scheduler;
+    [
+        (TEMPERATURE_JOB, &scheduler.temperature_cron),
+        (RELAY_JOB, &scheduler.relay_cron),
+        (VALVE_CONTROLLER_JOB, &scheduler.valve_controller_cron),
+        (STOVE_CONTROLLER_JOB, &scheduler.stove_controller_cron),
+        (RETENTION_JOB, &scheduler.retention_cron),
+    ]
+}
+
+/// Parses a cron expression with the same options as the job scheduler.
+pub fn parse_cron(expression: &str) -> Result<Cron, CronError> {
+    Cron::new(expression)
+        .with_seconds_required()
+        .with_dom_and_dow()
+        .parse()
+}
 
-/// How often to read temperatures from the sensors.
-const TEMPERATURE_CRON: &str = "0 */2 * * * *";
+/// Returns the time between the next two runs of a job.
+pub fn job_interval(expression: &str, now: DateTime<Utc>) -> Option<chrono::Duration> {
+    let cron = parse_cron(expression).ok()?;
+    let next = cron.find_next_occurrence(&now, false).ok()?;
+    let after_next = cron.find_next_occurrence(&next, false).ok()?;
+    Some(after_next - next)
+}
+
+/// Checks whether a job that last ran (or succeeded) at `since` missed more
+/// than the tolerated number of runs.
+pub fn is_overdue(
+    expression: &str,
+    since: DateTime<Utc>,
+    max_missed_runs: u32,
+    now: DateTime<Utc>,
+) -> bool {
+    match job_interval(expression, now) {
+        Some(interval) => now > since + interval * (max_missed_runs as i32 + 1),
+        None => true,
+    }
+}
+
+/// Returns the jobs that hang or stopped running: a run in progress for
+/// longer than `max_job_run_seconds`, or a controller job that has not run
+/// for more than `max_missed_job_runs` of its intervals (counting from the
+/// start before its first run). The relay and retention jobs run too rarely
+/// to tell a stopped scheduler apart within the watchdog timeout.
+///
+/// The retention job may take minutes on a large database, so its run time
+/// is not limited. If it held the database forever, the controller jobs
+/// waiting for it would be reported instead.
+pub fn stalled_jobs(
+    config: &HeatingConfiguration,
+    metrics: &Metrics,
+    now: DateTime<Utc>,
+) -> Vec<&'static str> {
+    let job_stats = metrics.job_stats();
+    let max_run = chrono::Duration::seconds(config.health.max_job_run_seconds as i64);
+
+    jobs(config)
+        .into_iter()
+        .filter(|(job, expression)| {
+            let stats = job_stats.get(job);
+            if *job != RETENTION_JOB
+                && stats
+                    .and_then(|stats| stats.running_since)
+                    .is_some_and(|running_since| now - running_since > max_run)
+            {
+                return true;
+            }
+
+            let last_run = stats
+                .and_then(|stats| stats.last_run)
+                .unwrap_or(metrics.started_at());
+            CONTROLLER_JOBS.contains(job)
+                && is_overdue(expression, last_run, config.health.max_missed_job_runs, now)
+        })
+        .map(|(job, _)| job)
+        .collect()
+}
+
+/// Lets the scheduled jobs run until it is stopped. Stopping waits for the
+/// jobs that are running, so none of them switches a relay afterwards.
+#[derive(Debug, Default)]
+pub struct JobGate {
+    stopped: RwLock<bool>,
+}
+
+impl JobGate {
+    /// Returns a guard to hold while a job runs, or `None` once stopped.
+    pub async fn enter(&self) -> Option<RwLockReadGuard<'_, bool>> {
+        let stopped = self.stopped.read().await;
+        (!*stopped).then_some(stopped)
+    }
+
+    /// Waits for the running jobs to finish and keeps new ones from running.
+    pub async fn stop(&self) {
+        *self.stopped.write().await = true;
+    }
+}
+
+/// The running job scheduler.
+pub struct Scheduler {
+    scheduler: JobScheduler,
+    gate: Arc<JobGate>,
+}
+
+impl Scheduler {
+    /// Waits for the running jobs and stops the scheduler.
+    pub async fn shutdown(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.gate.stop().await;
+        self.scheduler.shutdown().await?;
+        Ok(())
+    }
+}
 
 async fn temperature_job(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    gate: Arc<JobGate>,
 ) -> Result<Job, Box<dyn Error + Send + Sync>> {
     let config_clone = Arc::clone(&config);
     let conn_clone = Arc::clone(&conn);
 
     log::info!(
         "Creating job for reading temperatures: {}",
-        TEMPERATURE_CRON
+        config.scheduler.temperature_cron
     );
 
-    let job = Job::new_async(TEMPERATURE_CRON, move |_uuid, _l| {
-        let config_clone = Arc::clone(&config_clone);
-        let conn_clone = Arc::clone(&conn_clone);
+    let job = Job::new_async(
+        config.scheduler.temperature_cron.as_str(),
+        move |_uuid, _l| {
+            let config_clone = Arc::clone(&config_clone);
+            let conn_clone = Arc::clone(&conn_clone);
+            let metrics = Arc::clone(&metrics);
+            let events = Arc::clone(&events);
+            let gate = Arc::clone(&gate);
 
-        Box::pin(async move {
-            if let Err(e) = read_temperatures(config_clone.clone(), conn_clone.clone()).await {
-                log::error!("Error in temperature reading task: {}", e);
-            }
-        })
-    })?;
+            Box::pin(async move {
+                let Some(_running) = gate.enter().await else {
+                    return;
+                };
+                metrics.record_job_start(TEMPERATURE_JOB);
+                let started_at = Instant::now();
+                let result =
+                    read_temperatures(config_clone, conn_clone, Arc::clone(&metrics), events).await;
+                metrics.record_job_run(TEMPERATURE_JOB, started_at.elapsed(), result.is_ok());
+
+                if let Err(e) = result {
+                    log::error!("Error in temperature reading task: {}", e);
+                }
+            })
+        },
+    )?;
 
     Ok(job)
 }
@@ -51,18 +190,36 @@ async fn temperature_job(
 async fn relay_job(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    gate: Arc<JobGate>,
 ) -> Result<Job, Box<dyn Error + Send + Sync>> {
     let config_clone = Arc::clone(&config);
     let conn_clone = Arc::clone(&conn);
 
-    log::info!("Creating job for reading relays: {}", RELAY_CRON);
+    log::info!(
+        "Creating job for reading relays: {}",
+        config.scheduler.relay_cron
+    );
 
-    let job = Job::new_async(RELAY_CRON, move |_uuid, _l| {
+    let job = Job::new_async(config.scheduler.relay_cron.as_str(), move |_uuid, _l| {
         let config_clone = Arc::clone(&config_clone);
         let conn_clone = Arc::clone(&conn_clone);
+        let metrics = Arc::clone(&metrics);
+        let events = Arc::clone(&events);
+        let gate = Arc::clone(&gate);
 
         Box::pin(async move {
-            if let Err(e) = read_relay_states(config_clone, conn_clone).await {
+            let Some(_running) = gate.enter().await else {
+                return;
+            };
+            metrics.record_job_start(RELAY_JOB);
+            let started_at = Instant::now();
+            let result =
+                read_relay_states(config_clone, conn_clone, Arc::clone(&metrics), events).await;
+            metrics.record_job_run(RELAY_JOB, started_at.elapsed(), result.is_ok());
+
+            if let Err(e) = result {
                 log::error!("Error in relay state reading task: {}", e);
             }
         })
@@ -74,25 +231,48 @@ async fn relay_job(
 async fn valve_controller_job(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    gate: Arc<JobGate>,
 ) -> Result<Job, Box<dyn Error + Send + Sync>> {
     let config_clone = Arc::clone(&config);
     let conn_clone = Arc::clone(&conn);
 
     log::info!(
         "Creating job for controlling valves: {}",
-        VALVE_CONTROLLER_CRON
+        config.scheduler.valve_controller_cron
     );
 
-    let job = Job::new_async(VALVE_CONTROLLER_CRON, move |_uuid, _l| {
-        let config_clone = Arc::clone(&config_clone);
-        let conn_clone = Arc::clone(&conn_clone);
+    let job = Job::new_async(
+        config.scheduler.valve_controller_cron.as_str(),
+        move |_uuid, _l| {
+            let config_clone = Arc::clone(&config_clone);
+            let conn_clone = Arc::clone(&conn_clone);
+            let metrics = Arc::clone(&metrics);
+            let events = Arc::clone(&events);
+            let gate = Arc::clone(&gate);
 
-        Box::pin(async move {
-            if let Err(e) = controller::update_valves(config_clone, conn_clone).await {
-                log::error!("Error in heating control task: {}", e);
-            }
-        })
-    })?;
+            Box::pin(async move {
+                let Some(_running) = gate.enter().await else {
+                    return;
+                };
+                metrics.record_job_start(VALVE_CONTROLLER_JOB);
+                let started_at = Instant::now();
+                let result = controller::update_valves(
+                    config_clone,
+                    conn_clone,
+                    Arc::clone(&metrics),
+                    events,
+                )
+                .await;
+                metrics.record_job_run(VALVE_CONTROLLER_JOB, started_at.elapsed(), result.is_ok());
+
+                if let Err(e) = result {
+                    log::error!("Error in heating control task: {}", e);
+                }
+            })
+        },
+    )?;
 
     Ok(job)
 }
@@ -100,45 +280,290 @@ async fn valve_controller_job(
 async fn stove_controller_job(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    gate: Arc<JobGate>,
 ) -> Result<Job, Box<dyn Error + Send + Sync>> {
     let config_clone = Arc::clone(&config);
     let conn_clone = Arc::clone(&conn);
 
     log::info!(
         "Creating job for controlling stove: {}",
-        STOVE_CONTROLLER_CRON
+        config.scheduler.stove_controller_cron
     );
 
-    let job = Job::new_async(STOVE_CONTROLLER_CRON, move |_uuid, _l| {
-        let config_clone = Arc::clone(&config_clone);
-        let conn_clone = Arc::clone(&conn_clone);
+    let job = Job::new_async(
+        config.scheduler.stove_controller_cron.as_str(),
+        move |_uuid, _l| {
+            let config_clone = Arc::clone(&config_clone);
+            let conn_clone = Arc::clone(&conn_clone);
+            let metrics = Arc::clone(&metrics);
+            let events = Arc::clone(&events);
+            let gate = Arc::clone(&gate);
 
-        Box::pin(async move {
-            if let Err(e) = controller::update_stove_state(config_clone, conn_clone).await {
-                log::error!("Error in stove control task: {}", e);
-            }
-        })
-    })?;
+            Box::pin(async move {
+                let Some(_running) = gate.enter().await else {
+                    return;
+                };
+                metrics.record_job_start(STOVE_CONTROLLER_JOB);
+                let started_at = Instant::now();
+                let result = controller::update_stove_state(
+                    config_clone,
+                    conn_clone,
+                    Arc::clone(&metrics),
+                    events,
+                )
+                .await;
+                metrics.record_job_run(STOVE_CONTROLLER_JOB, started_at.elapsed(), result.is_ok());
+
+                if let Err(e) = result {
+                    log::error!("Error in stove control task: {}", e);
+                }
+            })
+        },
+    )?;
 
     Ok(job)
 }
 
+async fn retention_job(
+    config: Arc<HeatingConfiguration>,
+    conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    gate: Arc<JobGate>,
+) -> Result<Job, Box<dyn Error + Send + Sync>> {
+    let config_clone = Arc::clone(&config);
+    let conn_clone = Arc::clone(&conn);
+
+    log::info!(
+        "Creating job for downsampling old readings: {}",
+        config.scheduler.retention_cron
+    );
+
+    let job = Job::new_async(
+        config.scheduler.retention_cron.as_str(),
+        move |_uuid, _l| {
+            let config_clone = Arc::clone(&config_clone);
+            let conn_clone = Arc::clone(&conn_clone);
+            let metrics = Arc::clone(&metrics);
+            let gate = Arc::clone(&gate);
+
+            Box::pin(async move {
+                let Some(_running) = gate.enter().await else {
+                    return;
+                };
+                metrics.record_job_start(RETENTION_JOB);
+                let started_at = Instant::now();
+                let result = retention::apply_retention(config_clone, conn_clone).await;
+                metrics.record_job_run(RETENTION_JOB, started_at.elapsed(), result.is_ok());
+
+                if let Err(e) = result {
+                    log::error!("Error in retention task: {}", e);
+                }
+            })
+        },
+    )?;
+
+    Ok(job)
+}
+
+/// Runs the jobs at the times of their cron expressions in simulated time
+/// (e.g., a simulation or a replay advancing a manual clock).
+pub struct SimulatedScheduler {
+    jobs: Vec<(&'static str, Cron, DateTime<Utc>)>,
+}
+
+impl SimulatedScheduler {
+    pub fn new(
+        config: &HeatingConfiguration,
+        start: DateTime<Utc>,
+    ) -> Result<Self, NeuroheatError> {
+        let mut jobs = Vec::new();
+        for (job, expression) in self::jobs(config) {
+            let cron = parse_cron(expression).map_err(|e| {
+                NeuroheatError::ConfigurationError(format!("Invalid cron for job {}: {}", job, e))
+            })?;
+            let next_run = cron.find_next_occurrence(&start, false).map_err(|e| {
+                NeuroheatError::ConfigurationError(format!("No next run for job {}: {}", job, e))
+            })?;
+            jobs.push((job, cron, next_run));
+        }
+        Ok(SimulatedScheduler { jobs })
+    }
+
+    /// Runs the jobs that are due at `now`. Failing jobs are logged, like
+    /// in the real scheduler.
+    pub async fn run_due(
+        &mut self,
+        now: DateTime<Utc>,
+        config: &Arc<HeatingConfiguration>,
+        conn: &Arc<Mutex<Connection>>,
+        metrics: &Arc<Metrics>,
+        events: &Arc<Events>,
+    ) -> Result<(), NeuroheatError> {
+        for (job, cron, next_run) in &mut self.jobs {
+            if *next_run > now {
+                continue;
+            }
+            if let Err(e) = run_simulated_job(job, config, conn, metrics, events).await {
+                log::error!("Error in simulated {} job: {}", job, e);
+            }
+            *next_run = cron.find_next_occurrence(&now, false).map_err(|e| {
+                NeuroheatError::ConfigurationError(format!("No next run for job {}: {}", job, e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+async fn run_simulated_job(
+    job: &str,
+    config: &Arc<HeatingConfiguration>,
+    conn: &Arc<Mutex<Connection>>,
+    metrics: &Arc<Metrics>,
+    events: &Arc<Events>,
+) -> Result<(), NeuroheatError> {
+    let (config, conn, metrics, events) = (
+        Arc::clone(config),
+        Arc::clone(conn),
+        Arc::clone(metrics),
+        Arc::clone(events),
+    );
+
+    match job {
+        TEMPERATURE_JOB => read_temperatures(config, conn, metrics, events).await,
+        RELAY_JOB => read_relay_states(config, conn, metrics, events).await,
+        VALVE_CONTROLLER_JOB => controller::update_valves(config, conn, metrics, events).await,
+        STOVE_CONTROLLER_JOB => controller::update_stove_state(config, conn, metrics, events).await,
+        // old readings do not slow down a simulation
+        _ => Ok(()),
+    }
+}
+
 pub async fn start_scheduler(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
-) -> Result<JobScheduler, Box<dyn Error + Send + Sync>> {
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+) -> Result<Scheduler, Box<dyn Error + Send + Sync>> {
     let scheduler = JobScheduler::new().await?;
+    let gate = Arc::new(JobGate::default());
 
-    let temperature_job = temperature_job(Arc::clone(&config), Arc::clone(&conn)).await?;
-    let relay_job = relay_job(Arc::clone(&config), Arc::clone(&conn)).await?;
-    let valve_controller_job = valve_controller_job(Arc::clone(&config), Arc::clone(&conn)).await?;
-    let stove_controller_job = stove_controller_job(Arc::clone(&config), Arc::clone(&conn)).await?;
+    let temperature_job = temperature_job(
+        Arc::clone(&config),
+        Arc::clone(&conn),
+        Arc::clone(&metrics),
+        Arc::clone(&events),
+        Arc::clone(&gate),
+    )
+    .await?;
+    let relay_job = relay_job(
+        Arc::clone(&config),
+        Arc::clone(&conn),
+        Arc::clone(&metrics),
+        Arc::clone(&events),
+        Arc::clone(&gate),
+    )
+    .await?;
+    let valve_controller_job = valve_controller_job(
+        Arc::clone(&config),
+        Arc::clone(&conn),
+        Arc::clone(&metrics),
+        Arc::clone(&events),
+        Arc::clone(&gate),
+    )
+    .await?;
+    let stove_controller_job = stove_controller_job(
+        Arc::clone(&config),
+        Arc::clone(&conn),
+        Arc::clone(&metrics),
+        Arc::clone(&events),
+        Arc::clone(&gate),
+    )
+    .await?;
+    let retention_job = retention_job(
+        Arc::clone(&config),
+        Arc::clone(&conn),
+        Arc::clone(&metrics),
+        Arc::clone(&gate),
+    )
+    .await?;
 
     scheduler.add(temperature_job).await?;
     scheduler.add(relay_job).await?;
     scheduler.add(valve_controller_job).await?;
     scheduler.add(stove_controller_job).await?;
+    scheduler.add(retention_job).await?;
     scheduler.start().await?;
 
-    Ok(scheduler)
+    Ok(Scheduler { scheduler, gate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::config;
+    use std::time::Duration;
+
+    #[test]
+    fn reports_hung_and_stopped_jobs() {
+        let config = config();
+        let metrics = Metrics::new(config.clock.clone());
+        for job in [TEMPERATURE_JOB, VALVE_CONTROLLER_JOB, STOVE_CONTROLLER_JOB] {
+            metrics.record_job_run(job, Duration::ZERO, true);
+        }
+        metrics.record_job_start(RELAY_JOB);
+        let now = config.clock.now();
+
+        assert!(stalled_jobs(&config, &metrics, now).is_empty());
+        // The relay run hangs.
+        assert_eq!(
+            stalled_jobs(&config, &metrics, now + chrono::Duration::seconds(61)),
+            [RELAY_JOB]
+        );
+
+        // Without runs for more than two intervals, the scheduler stopped.
+        metrics.record_job_run(RELAY_JOB, Duration::ZERO, true);
+        assert_eq!(
+            stalled_jobs(&config, &metrics, now + chrono::Duration::minutes(7)),
+            [TEMPERATURE_JOB, VALVE_CONTROLLER_JOB]
+        );
+    }
+
+    #[test]
+    fn lets_the_retention_job_run_for_long() {
+        let config = config();
+        let metrics = Metrics::new(config.clock.clone());
+        metrics.record_job_start(RETENTION_JOB);
+        let now = config.clock.now();
+
+        // The controller jobs keep running while the downsampling does.
+        for job in [TEMPERATURE_JOB, VALVE_CONTROLLER_JOB, STOVE_CONTROLLER_JOB] {
+            metrics.record_job_run(job, Duration::ZERO, true);
+        }
+        assert!(stalled_jobs(&config, &metrics, now + chrono::Duration::minutes(1)).is_empty());
+
+        metrics.record_job_start(TEMPERATURE_JOB);
+        assert_eq!(
+            stalled_jobs(&config, &metrics, now + chrono::Duration::minutes(2)),
+            [TEMPERATURE_JOB]
+        );
+    }
+
+    #[tokio::test]
+    async fn stopping_waits_for_running_jobs() {
+        let gate = Arc::new(JobGate::default());
+        let running = gate.enter().await.unwrap();
+
+        let stopping = tokio::spawn({
+            let gate = Arc::clone(&gate);
+            async move { gate.stop().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!stopping.is_finished());
+
+        drop(running);
+        stopping.await.unwrap();
+        assert!(gate.enter().await.is_none());
+    }
 }
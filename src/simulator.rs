@@ -0,0 +1,478 @@
+use chrono::{DateTime, Duration, Local, Timelike, Utc};
+use rusqlite::Connection;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::ManualClock;
+use crate::db;
+use crate::devices::VirtualDevices;
+use crate::error::NeuroheatError;
+use crate::events::Events;
+use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
+use crate::relay::{self, RelayController, VirtualRelayController};
+use crate::report::Report;
+use crate::scheduler::SimulatedScheduler;
+use crate::temperature_sensor::VirtualTemperatureSensor;
+
+/// The resolution of the DS18B20 sensors in °C.
+const SENSOR_RESOLUTION: f32 = 0.0625;
+
+/// The thermal model of the simulated house. Rooms that are not listed use
+/// the defaults, scaled by the area of the room.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HouseConfiguration {
+    /// How far the simulated time advances per step.
+    pub step_seconds: u32,
+    /// The warmest the floor gets (the loops are fed through a mixing valve).
+    pub floor_max_temperature: f32,
+    pub outdoor: OutdoorProfile,
+    pub stove: StoveModel,
+    pub rooms: HashMap<String, RoomModel>,
+}
+
+/// A daily outdoor temperature cycle.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OutdoorProfile {
+    pub mean_temperature: f32,
+    /// The difference between the mean and the coldest (warmest) temperature.
+    pub amplitude: f32,
+    /// The local hour of the coldest temperature.
+    pub coldest_hour: f32,
+}
+
+/// The stove heats the water in the heating pipe towards its supply
+/// temperature while on and lets it cool down towards the idle temperature
+/// while off.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct StoveModel {
+    pub supply_temperature: f32,
+    pub idle_temperature: f32,
+    /// The time constant of heating up.
+    pub heat_up_minutes: f32,
+    /// The time constant of cooling down.
+    pub cool_down_minutes: f32,
+}
+
+/// The thermal properties of a room.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RoomModel {
+    pub initial_temperature: f32,
+    /// The heat lost to the outside in W/K (2.5 W/K per m² by default).
+    pub heat_loss: Option<f32>,
+    /// The heat capacity of the air, walls and furniture in kJ/K (300 kJ/K
+    /// per m² by default).
+    pub thermal_mass: Option<f32>,
+    /// The heat emitted by the floor in W/(m²·K).
+    pub floor_emission: f32,
+    /// The time constant of the floor following the water (or the room).
+    pub floor_lag_minutes: f32,
+}
+
+impl Default for HouseConfiguration {
+    fn default() -> Self {
+        HouseConfiguration {
+            step_seconds: 15,
+            floor_max_temperature: 30.0,
+            outdoor: OutdoorProfile::default(),
+            stove: StoveModel::default(),
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+impl Default for OutdoorProfile {
+    fn default() -> Self {
+        OutdoorProfile {
+            mean_temperature: 0.0,
+            amplitude: 4.0,
+            coldest_hour: 5.0,
+        }
+    }
+}
+
+impl Default for StoveModel {
+    fn default() -> Self {
+        StoveModel {
+            supply_temperature: 50.0,
+            idle_temperature: 20.0,
+            heat_up_minutes: 15.0,
+            cool_down_minutes: 60.0,
+        }
+    }
+}
+
+impl Default for RoomModel {
+    fn default() -> Self {
+        RoomModel {
+            initial_temperature: 20.0,
+            heat_loss: None,
+            thermal_mass: None,
+            floor_emission: 10.0,
+            floor_lag_minutes: 120.0,
+        }
+    }
+}
+
+impl HouseConfiguration {
+    /// Reads the house model from a JSON file.
+    pub fn from_file(path: &str, config: &HeatingConfiguration) -> Result<Self, NeuroheatError> {
+        let file = File::open(path).map_err(|e| {
+            let err_msg = format!("Failed to open house file {}: {}", path, e);
+            log::error!("{}", err_msg);
+            NeuroheatError::ConfigurationError(err_msg)
+        })?;
+        let house: HouseConfiguration =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+                let err_msg = format!("Failed to parse house file {}: {}", path, e);
+                log::error!("{}", err_msg);
+                NeuroheatError::ConfigurationError(err_msg)
+            })?;
+
+        house.validate(config).map_err(|e| {
+            let err_msg = format!("Invalid house file {}: {}", path, e);
+            log::error!("{}", err_msg);
+            NeuroheatError::ConfigurationError(err_msg)
+        })?;
+
+        Ok(house)
+    }
+
+    fn validate(&self, config: &HeatingConfiguration) -> Result<(), String> {
+        if self.step_seconds == 0 {
+            return Err("step_seconds must be positive".to_string());
+        }
+        if self.stove.heat_up_minutes <= 0.0 || self.stove.cool_down_minutes <= 0.0 {
+            return Err("stove time constants must be positive".to_string());
+        }
+
+        for (key, room) in &self.rooms {
+            if !config.rooms.iter().any(|r| &r.key == key) {
+                return Err(format!("room {} is not in the heating configuration", key));
+            }
+            if room.floor_lag_minutes <= 0.0 {
+                return Err(format!("{}.floor_lag_minutes must be positive", key));
+            }
+            if room.heat_loss.is_some_and(|heat_loss| heat_loss < 0.0) {
+                return Err(format!("{}.heat_loss must not be negative", key));
+            }
+            if room
+                .thermal_mass
+                .is_some_and(|thermal_mass| thermal_mass <= 0.0)
+            {
+                return Err(format!("{}.thermal_mass must be positive", key));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OutdoorProfile {
+    pub fn temperature_at(&self, time: DateTime<Utc>) -> f32 {
+        let local = time.with_timezone(&Local);
+        let hour = local.hour() as f32 + local.minute() as f32 / 60.0;
+        self.mean_temperature
+            - self.amplitude * (2.0 * PI * (hour - self.coldest_hour) / 24.0).cos()
+    }
+}
+
+/// Moves a value towards its target with the given time constant.
+fn approach(value: f32, target: f32, elapsed_minutes: f32, time_constant_minutes: f32) -> f32 {
+    target + (value - target) * (-elapsed_minutes / time_constant_minutes).exp()
+}
+
+fn quantize(temperature: f32) -> f32 {
+    (temperature / SENSOR_RESOLUTION).round() * SENSOR_RESOLUTION
+}
+
+struct SimulatedRoom {
+    area: f32,
+    heat_loss: f32,
+    thermal_mass: f32,
+    floor_emission: f32,
+    floor_lag_minutes: f32,
+    air_temperature: f32,
+    floor_temperature: f32,
+    sensor: Arc<VirtualTemperatureSensor>,
+    valve: Arc<VirtualRelayController>,
+}
+
+/// The physical state of the simulated house.
+struct House<'a> {
+    model: &'a HouseConfiguration,
+    rooms: Vec<SimulatedRoom>,
+    supply_temperature: f32,
+    outdoor_temperature: f32,
+    pipe_sensor: Arc<VirtualTemperatureSensor>,
+    outdoor_sensor: Option<Arc<VirtualTemperatureSensor>>,
+    stove: Arc<VirtualRelayController>,
+}
+
+impl<'a> House<'a> {
+    fn new(
+        model: &'a HouseConfiguration,
+        config: &HeatingConfiguration,
+        devices: &VirtualDevices,
+        start: DateTime<Utc>,
+    ) -> Self {
+        let default_room = RoomModel::default();
+        let rooms = config
+            .rooms
+            .iter()
+            .map(|room| {
+                let room_model = model.rooms.get(&room.key).unwrap_or(&default_room);
+                SimulatedRoom {
+                    area: room.area,
+                    heat_loss: room_model.heat_loss.unwrap_or(2.5 * room.area),
+                    thermal_mass: room_model.thermal_mass.unwrap_or(300.0 * room.area),
+                    floor_emission: room_model.floor_emission,
+                    floor_lag_minutes: room_model.floor_lag_minutes,
+                    air_temperature: room_model.initial_temperature,
+                    floor_temperature: room_model.initial_temperature,
+                    sensor: devices.sensor(&room.sensor_id),
+                    valve: devices.relay_at(room.valve_pin),
+                }
+            })
+            .collect();
+
+        let house = House {
+            model,
+            rooms,
+            supply_temperature: model.stove.idle_temperature,
+            outdoor_temperature: model.outdoor.temperature_at(start),
+            pipe_sensor: devices.sensor(&config.pipe_sensor_id),
+            outdoor_sensor: config
+                .outdoor_sensor_id
+                .as_ref()
+                .map(|id| devices.sensor(id)),
+            stove: devices.relay_at(config.stove_pin),
+        };
+        house.update_sensors();
+        house
+    }
+
+    fn stove_on(&self) -> bool {
+        self.stove.read_state().unwrap_or(false)
+    }
+
+    /// Advances the physical state by the given duration.
+    fn step(&mut self, duration: Duration, now: DateTime<Utc>) {
+        let minutes = duration.num_seconds() as f32 / 60.0;
+        let stove = &self.model.stove;
+
+        self.outdoor_temperature = self.model.outdoor.temperature_at(now);
+        self.supply_temperature = if self.stove_on() {
+            approach(
+                self.supply_temperature,
+                stove.supply_temperature,
+                minutes,
+                stove.heat_up_minutes,
+            )
+        } else {
+            approach(
+                self.supply_temperature,
+                stove.idle_temperature,
+                minutes,
+                stove.cool_down_minutes,
+            )
+        };
+
+        let floor_supply = self
+            .supply_temperature
+            .min(self.model.floor_max_temperature);
+        for room in &mut self.rooms {
+            let valve_open = room.valve.read_state().unwrap_or(false);
+            let floor_target = if valve_open {
+                floor_supply
+            } else {
+                room.air_temperature
+            };
+            room.floor_temperature = approach(
+                room.floor_temperature,
+                floor_target,
+                minutes,
+                room.floor_lag_minutes,
+            );
+
+            let power =
+                room.floor_emission * room.area * (room.floor_temperature - room.air_temperature)
+                    - room.heat_loss * (room.air_temperature - self.outdoor_temperature);
+            room.air_temperature +=
+                power * duration.num_seconds() as f32 / (room.thermal_mass * 1000.0);
+        }
+
+        self.update_sensors();
+    }
+
+    fn update_sensors(&self) {
+        for room in &self.rooms {
+            room.sensor.set(Some(quantize(room.air_temperature)));
+        }
+        self.pipe_sensor
+            .set(Some(quantize(self.supply_temperature)));
+        if let Some(outdoor_sensor) = &self.outdoor_sensor {
+            outdoor_sensor.set(Some(quantize(self.outdoor_temperature)));
+        }
+    }
+}
+
+/// Runs the scheduler jobs against the simulated house for the given time
+/// span. Jobs run at the times of their cron expressions in simulated time,
+/// so a day is simulated in seconds. Readings and relay states are stored
+/// in the given database.
+pub async fn simulate(
+    mut config: HeatingConfiguration,
+    model: &HouseConfiguration,
+    conn: Arc<Mutex<Connection>>,
+    start: DateTime<Utc>,
+    duration: Duration,
+) -> Result<Report, NeuroheatError> {
+    let devices = VirtualDevices::new();
+    let clock = Arc::new(ManualClock::new(start));
+    config.attach_devices(&devices);
+    config.clock = clock.clone();
+    let config = Arc::new(config);
+    let metrics = Arc::new(Metrics::new(config.clock.clone()));
+    let events = Arc::new(Events::new());
+
+    db::init(&conn, &config)?;
+    relay::apply_safe_state(&config, &conn, &metrics, &events)?;
+
+    let mut house = House::new(model, &config, &devices, start);
+    let mut report = Report::new(&config, start);
+
+    let mut scheduler = SimulatedScheduler::new(&config, start)?;
+
+    let step = Duration::seconds(model.step_seconds as i64);
+    let end = start + duration;
+    let mut now = start;
+
+    while now < end {
+        now += step;
+        house.step(step, now);
+        clock.set(now);
+
+        scheduler
+            .run_due(now, &config, &conn, &metrics, &events)
+            .await?;
+
+        let outdoor_temperature = config
+            .outdoor_sensor_id
+            .as_ref()
+            .map(|_| house.outdoor_temperature);
+        for (room, simulated) in config.rooms.iter().zip(&house.rooms) {
+            report.record_room(
+                &room.key,
+                simulated.air_temperature,
+                room.get_expected_temperature(None, outdoor_temperature, now),
+                simulated.valve.read_state().unwrap_or(false),
+                step,
+            );
+        }
+        report.record_stove(house.stove_on(), step);
+    }
+    report.to = now;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature_sensor::TemperatureSensor;
+    use crate::testing::{config, start};
+
+    fn cold_house() -> HouseConfiguration {
+        let mut house = HouseConfiguration::default();
+        for key in ["office", "kitchen"] {
+            let room = RoomModel {
+                initial_temperature: 18.0,
+                ..RoomModel::default()
+            };
+            house.rooms.insert(key.to_string(), room);
+        }
+        house
+    }
+
+    #[test]
+    fn open_valves_warm_rooms_once_the_stove_heats() {
+        let model = cold_house();
+        let config = config();
+        let devices = VirtualDevices::new();
+        let mut house = House::new(&model, &config, &devices, start());
+        devices.relay_at(config.stove_pin).set_state(true).unwrap();
+        devices
+            .relay_at(config.rooms[0].valve_pin)
+            .set_state(true)
+            .unwrap();
+
+        let step = Duration::seconds(15);
+        let mut now = start();
+        for _ in 0..(4 * 60 * 6) {
+            now += step;
+            house.step(step, now);
+        }
+
+        let (office, kitchen) = (&house.rooms[0], &house.rooms[1]);
+        assert!(house.supply_temperature > 45.0);
+        assert!(office.floor_temperature > 25.0);
+        assert!(office.air_temperature > 19.0);
+        assert!(kitchen.air_temperature < 18.0);
+        assert_eq!(
+            devices.sensor("office-sensor").read().unwrap(),
+            quantize(office.air_temperature)
+        );
+    }
+
+    #[tokio::test]
+    async fn heats_a_cold_house() {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+
+        let report = simulate(
+            config(),
+            &cold_house(),
+            conn.clone(),
+            start(),
+            Duration::hours(12),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.to, start() + Duration::hours(12));
+        assert!(report.stove.starts >= 1);
+        for room in &report.rooms {
+            assert!(room.valve.on_minutes > 0.0, "{} was not heated", room.key);
+            assert!(room.max_temperature.unwrap() > 20.8);
+        }
+
+        let readings: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM temperatures WHERE key = 'office'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(readings, 12 * 30);
+    }
+
+    #[test]
+    fn rejects_unknown_rooms() {
+        let mut house = HouseConfiguration::default();
+        house
+            .rooms
+            .insert("attic".to_string(), RoomModel::default());
+
+        assert!(house.validate(&config()).is_err());
+    }
+}
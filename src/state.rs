@@ -0,0 +1,61 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The latest temperature reading of a key.
+#[derive(Debug, Clone, Serialize)]
+pub struct TemperatureReading {
+    pub key: String,
+    pub label: String,
+    pub timestamp: DateTime<Utc>,
+    pub temperature: f32,
+    pub expected_temperature: Option<f32>,
+}
+
+/// The current state of a key (a room, the pipe, the outdoor sensor or the
+/// stove). Fields that do not apply to the key are `null`.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyState {
+    pub label: String,
+    /// When the latest temperature (or state for the stove) was recorded.
+    pub timestamp: DateTime<Utc>,
+    pub temperature: Option<f32>,
+    pub expected_temperature: Option<f32>,
+    pub heating_enabled: Option<bool>,
+}
+
+impl TemperatureReading {
+    /// The string-valued representation served by the unversioned API.
+    pub fn to_legacy(&self) -> HashMap<&'static str, String> {
+        let mut result = HashMap::from([
+            ("key", self.key.clone()),
+            ("label", self.label.clone()),
+            ("timestamp", self.timestamp.to_string()),
+            ("temperature", self.temperature.to_string()),
+        ]);
+        if let Some(expected_temperature) = self.expected_temperature {
+            result.insert("expected_temperature", expected_temperature.to_string());
+        }
+        result
+    }
+}
+
+impl KeyState {
+    /// The string-valued representation served by the unversioned API.
+    pub fn to_legacy(&self) -> HashMap<&'static str, String> {
+        let mut result = HashMap::from([
+            ("label", self.label.clone()),
+            ("timestamp", self.timestamp.to_string()),
+        ]);
+        if let Some(temperature) = self.temperature {
+            result.insert("temperature", temperature.to_string());
+        }
+        if let Some(expected_temperature) = self.expected_temperature {
+            result.insert("expected_temperature", expected_temperature.to_string());
+        }
+        if let Some(heating_enabled) = self.heating_enabled {
+            result.insert("heating_enabled", heating_enabled.to_string());
+        }
+        result
+    }
+}
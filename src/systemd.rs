@@ -0,0 +1,74 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
+use crate::scheduler;
+
+/// Sends a state change (e.g., `READY=1`) to the service manager. Does
+/// nothing when not started by systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // a leading `@` denotes a socket in the abstract namespace
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path.as_ref())?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+
+    if let Err(e) = result {
+        log::error!("Failed to notify systemd ({}): {}", state, e);
+    }
+}
+
+/// Returns the watchdog timeout configured with `WatchdogSec` if it applies
+/// to this process.
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the systemd watchdog at half the configured timeout, but only while
+/// all scheduler jobs keep running. A hung job (e.g., one waiting for the
+/// database lock forever) stops the pings and systemd restarts the service.
+pub fn spawn_watchdog(config: Arc<HeatingConfiguration>, metrics: Arc<Metrics>) {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    log::info!("Pinging the systemd watchdog every {:?}", timeout / 2);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout / 2);
+        let mut stalled = Vec::new();
+
+        loop {
+            interval.tick().await;
+
+            let now_stalled = scheduler::stalled_jobs(&config, &metrics, config.clock.now());
+            if now_stalled.is_empty() {
+                notify("WATCHDOG=1");
+            } else if now_stalled != stalled {
+                log::error!(
+                    "Jobs {} are not running. Not pinging the systemd watchdog.",
+                    now_stalled.join(", ")
+                );
+            }
+            stalled = now_stalled;
+        }
+    });
+}
@@ -0,0 +1,144 @@
+use chrono::{DateTime, Duration, Local, SubsecRound, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::NeuroheatError;
+use crate::heating_configuration::Room;
+
+/// The lowest temperature that can be requested through an override.
+const MIN_OVERRIDE_TEMPERATURE: f32 = 5.0;
+
+/// The highest temperature that can be requested through an override.
+const MAX_OVERRIDE_TEMPERATURE: f32 = 30.0;
+
+/// A temporary expected temperature for a room that takes precedence
+/// over its temperature schedule until it expires.
+#[derive(Debug, Clone, Serialize)]
+pub struct TemperatureOverride {
+    /// The key of the room the override applies to.
+    pub key: String,
+    /// The expected temperature while the override is active.
+    pub temperature: f32,
+    /// When the override was created.
+    pub created_at: DateTime<Utc>,
+    /// When the override stops applying.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The body of a request creating an override. Without `duration_minutes`,
+/// the override lasts until the next change in the room's schedule.
+#[derive(Debug, Deserialize)]
+pub struct OverrideRequest {
+    pub temperature: f32,
+    pub duration_minutes: Option<u32>,
+}
+
+impl OverrideRequest {
+    /// Validates the request and builds the override for the given room.
+    pub fn into_override(
+        self,
+        room: &Room,
+        now: DateTime<Utc>,
+    ) -> Result<TemperatureOverride, NeuroheatError> {
+        if !(MIN_OVERRIDE_TEMPERATURE..=MAX_OVERRIDE_TEMPERATURE).contains(&self.temperature) {
+            return Err(NeuroheatError::ValidationError(format!(
+                "Override temperature {:.1}°C is outside of the allowed range ({:.1}-{:.1}°C)",
+                self.temperature, MIN_OVERRIDE_TEMPERATURE, MAX_OVERRIDE_TEMPERATURE
+            )));
+        }
+
+        let expires_at = match self.duration_minutes {
+            Some(0) => {
+                return Err(NeuroheatError::ValidationError(
+                    "Override duration must be positive".to_string(),
+                ))
+            }
+            Some(minutes) => now + Duration::minutes(minutes as i64),
+            None => next_schedule_change(room, now)?,
+        };
+
+        // timestamps are stored with second precision
+        Ok(TemperatureOverride {
+            key: room.key.clone(),
+            temperature: self.temperature,
+            created_at: now.trunc_subsecs(0),
+            expires_at: expires_at.trunc_subsecs(0),
+        })
+    }
+}
+
+fn next_schedule_change(room: &Room, now: DateTime<Utc>) -> Result<DateTime<Utc>, NeuroheatError> {
+    let local_now = now.with_timezone(&Local).naive_local();
+
+    room.next_schedule_change(&local_now)
+        .and_then(|change| Local.from_local_datetime(&change).earliest())
+        .map(|change| change.with_timezone(&Utc))
+        .ok_or_else(|| {
+            NeuroheatError::ValidationError(format!(
+                "No upcoming schedule change for room {}; provide duration_minutes",
+                room.name
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config_json_with_schedule, parse_config};
+
+    /// Heated from 06:00 to 22:00 local time.
+    const SCHEDULE: &str = r#"[
+      { "start": "00:00", "end": "06:00", "temperature": 18.0 },
+      { "start": "06:00", "end": "22:00", "temperature": 21.0 },
+      { "start": "22:00", "end": "24:00", "temperature": 18.0 }
+    ]"#;
+
+    fn local(day: u32, hour: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(2024, 1, day, hour, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn request(duration_minutes: Option<u32>) -> OverrideRequest {
+        OverrideRequest {
+            temperature: 23.0,
+            duration_minutes,
+        }
+    }
+
+    #[test]
+    fn lasts_until_the_next_schedule_change() {
+        let config = parse_config(&config_json_with_schedule(SCHEDULE)).unwrap();
+        let office = &config.rooms[0];
+
+        let daytime = request(None).into_override(office, local(15, 12)).unwrap();
+        assert_eq!(daytime.expires_at, local(15, 22));
+        let night = request(None).into_override(office, local(15, 23)).unwrap();
+        assert_eq!(night.expires_at, local(16, 6));
+
+        let timed = request(Some(90))
+            .into_override(office, local(15, 23))
+            .unwrap();
+        assert_eq!(timed.expires_at, local(16, 0) + Duration::minutes(30));
+    }
+
+    #[test]
+    fn needs_a_duration_without_schedule_changes() {
+        let config = parse_config(&config_json_with_schedule(
+            r#"[{ "start_hour": 0, "end_hour": 24, "temperature": 21.0 }]"#,
+        ))
+        .unwrap();
+        let office = &config.rooms[0];
+
+        assert!(matches!(
+            request(None).into_override(office, local(15, 12)),
+            Err(NeuroheatError::ValidationError(_))
+        ));
+        assert!(request(Some(60))
+            .into_override(office, local(15, 12))
+            .is_ok());
+        assert!(request(Some(0))
+            .into_override(office, local(15, 12))
+            .is_err());
+    }
+}
@@ -1,11 +1,15 @@
+use chrono::{DateTime, Utc};
 use rusqlite::Connection;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use crate::controller::{get_outdoor_temperature, OUTDOOR_KEY};
 use crate::error::NeuroheatError;
-use crate::heating_configuration::HeatingConfiguration;
+use crate::events::Events;
+use crate::heating_configuration::{HeatingConfiguration, SensorRange};
+use crate::metrics::Metrics;
 use crate::repo;
 
 const W1_PATH_PREFIX: &str = "/sys/devices/w1_bus_master1/";
@@ -41,7 +45,7 @@ impl TemperatureSensor for DS18B20 {
     fn read(&self) -> Result<f32, NeuroheatError> {
         let path = Path::new(&self.file_path);
 
-        let file = File::open(&path)?;
+        let file = File::open(path)?;
         let reader = io::BufReader::new(file);
 
         let mut lines = reader.lines();
@@ -68,12 +72,7 @@ impl TemperatureSensor for DS18B20 {
             })?;
             let temperature = temp_millidegrees as f32 / 1000.0;
 
-            if temperature < 0.0 || temperature > 50.0 {
-                let err_msg = format!("Temperature out of range: {:.1}°C", temperature);
-                Err(NeuroheatError::SensorError(err_msg))
-            } else {
-                Ok(temperature)
-            }
+            Ok(temperature)
         } else {
             let err_msg = "Temperature data not found".to_string();
             log::error!("{}", err_msg);
@@ -82,29 +81,113 @@ impl TemperatureSensor for DS18B20 {
     }
 }
 
+/// An in-memory sensor that reports the last temperature set on it (or an
+/// error while none is set). Used in tests and the simulator instead of the
+/// 1-Wire bus.
+#[derive(Debug, Default)]
+pub struct VirtualTemperatureSensor {
+    temperature: Mutex<Option<f32>>,
+}
+
+impl VirtualTemperatureSensor {
+    /// Sets the reported temperature; `None` makes reads fail.
+    pub fn set(&self, temperature: Option<f32>) {
+        *self.temperature.lock().unwrap_or_else(|e| e.into_inner()) = temperature;
+    }
+}
+
+impl TemperatureSensor for VirtualTemperatureSensor {
+    fn read(&self) -> Result<f32, NeuroheatError> {
+        self.temperature
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .ok_or_else(|| NeuroheatError::SensorError("No temperature set".to_string()))
+    }
+}
+
 pub async fn read_temperatures(
     config: Arc<HeatingConfiguration>,
     conn: Arc<Mutex<Connection>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
 ) -> Result<(), NeuroheatError> {
+    let now = config.clock.now();
+
     if let Some(pipe_sensor) = &config.pipe_sensor {
         match pipe_sensor.read() {
             Ok(temp) => {
-                log::info!("Pipe Temperature: {:.1}°C", temp);
-                if let Err(e) = repo::store_temperature(&conn, "pipe", temp, None) {
-                    log::error!("Failed to store pipe temperature: {}", e);
+                if accept_reading(
+                    &conn,
+                    &metrics,
+                    "pipe",
+                    &config.pipe_sensor_range,
+                    temp,
+                    now,
+                ) {
+                    log::info!("Pipe Temperature: {:.1}°C", temp);
+                    metrics.set_pipe_temperature(temp);
+                    match repo::store_temperature(&conn, "pipe", temp, None, now) {
+                        Ok(()) => events.temperature("pipe", temp, None, now),
+                        Err(e) => log::error!("Failed to store pipe temperature: {}", e),
+                    }
                 }
             }
             Err(e) => {
                 log::warn!("Error reading pipe temperature: {}", e);
+                metrics.record_sensor_error("pipe");
             }
         }
     }
 
+    if let Some(outdoor_sensor) = &config.outdoor_sensor {
+        match outdoor_sensor.read() {
+            Ok(temp) => {
+                if accept_reading(
+                    &conn,
+                    &metrics,
+                    OUTDOOR_KEY,
+                    &config.outdoor_sensor_range,
+                    temp,
+                    now,
+                ) {
+                    log::info!("Outdoor Temperature: {:.1}°C", temp);
+                    metrics.set_outdoor_temperature(temp);
+                    match repo::store_temperature(&conn, OUTDOOR_KEY, temp, None, now) {
+                        Ok(()) => events.temperature(OUTDOOR_KEY, temp, None, now),
+                        Err(e) => log::error!("Failed to store outdoor temperature: {}", e),
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("Error reading outdoor temperature: {}", e);
+                metrics.record_sensor_error(OUTDOOR_KEY);
+            }
+        }
+    }
+
+    let outdoor_temperature = get_outdoor_temperature(&config, &conn, now);
+
     for room in &config.rooms {
         if let Some(sensor) = &room.sensor {
             match sensor.read() {
                 Ok(temp) => {
-                    let expected_temp = room.get_expected_temperature();
+                    if !accept_reading(&conn, &metrics, &room.key, &room.sensor_range, temp, now) {
+                        continue;
+                    }
+
+                    let temperature_override =
+                        match repo::get_active_override(&conn, &room.key, now) {
+                            Ok(temperature_override) => temperature_override,
+                            Err(e) => {
+                                log::error!("Failed to get override for room {}: {}", room.name, e);
+                                None
+                            }
+                        };
+                    let expected_temp = room.get_expected_temperature(
+                        temperature_override.as_ref(),
+                        outdoor_temperature,
+                        now,
+                    );
                     match expected_temp {
                         Some(expected) => {
                             log::info!(
@@ -118,12 +201,17 @@ pub async fn read_temperatures(
                             log::info!("Room: {}, Temperature: {:.1}°C", room.name, temp);
                         }
                     }
-                    if let Err(e) = repo::store_temperature(&conn, &room.key, temp, expected_temp) {
-                        log::error!("Failed to store temperature for room {}: {}", room.name, e);
+                    metrics.set_room_temperature(&room.key, temp, expected_temp);
+                    match repo::store_temperature(&conn, &room.key, temp, expected_temp, now) {
+                        Ok(()) => events.temperature(&room.key, temp, expected_temp, now),
+                        Err(e) => {
+                            log::error!("Failed to store temperature for room {}: {}", room.name, e)
+                        }
                     }
                 }
                 Err(e) => {
                     log::warn!("Error reading temperature for {}: {}", room.name, e);
+                    metrics.record_sensor_error(&room.key);
                 }
             }
         } else {
@@ -133,3 +221,110 @@ pub async fn read_temperatures(
 
     Ok(())
 }
+
+/// Checks a reading against the plausibility range of the sensor. Readings
+/// that do not fit are recorded as rejected (with the reason) instead of
+/// being stored as temperatures.
+fn accept_reading(
+    conn: &Arc<Mutex<Connection>>,
+    metrics: &Metrics,
+    key: &str,
+    range: &SensorRange,
+    temperature: f32,
+    now: DateTime<Utc>,
+) -> bool {
+    let previous = match repo::get_latest_reading(conn, key) {
+        Ok(previous) => previous,
+        Err(e) => {
+            log::error!("Failed to get previous reading for key {}: {}", key, e);
+            None
+        }
+    };
+
+    match range.check(temperature, previous, now) {
+        Ok(()) => true,
+        Err(reason) => {
+            log::warn!(
+                "Rejected temperature {:.1}°C for key {}: {}",
+                temperature,
+                key,
+                reason
+            );
+            metrics.record_rejected_temperature(key);
+            if let Err(e) = repo::store_rejected_temperature(conn, key, temperature, &reason, now) {
+                log::error!(
+                    "Failed to store rejected temperature for key {}: {}",
+                    key,
+                    e
+                );
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::relay::RelayController;
+    use crate::repo;
+    use crate::testing::{config_json_with, Harness, OFFICE_PIN};
+
+    fn rejected(harness: &Harness) -> Vec<(f32, String)> {
+        db::with_locked_connection(&harness.conn, |conn| {
+            conn.prepare("SELECT temperature, reason FROM rejected_temperatures ORDER BY id")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .unwrap()
+    }
+
+    fn latest(harness: &Harness) -> Option<f32> {
+        repo::get_latest_reading(&harness.conn, "office")
+            .unwrap()
+            .map(|(temperature, _)| temperature)
+    }
+
+    #[tokio::test]
+    async fn stores_rejected_readings_apart() {
+        let harness = Harness::with_config(&config_json_with(
+            r#""sensor_range": { "min": 0.0, "max": 40.0, "max_change_per_minute": 0.5 }"#,
+        ));
+        let office = harness.sensor("office-sensor");
+
+        // The first reading has nothing to be compared with.
+        office.set(Some(39.0));
+        harness.read_temperatures(1).await;
+        assert_eq!(latest(&harness), Some(39.0));
+
+        harness.advance(60);
+        office.set(Some(20.0));
+        harness.read_temperatures(5).await;
+        assert_eq!(latest(&harness), Some(20.0));
+
+        office.set(Some(45.0));
+        harness.read_temperatures(1).await;
+        office.set(Some(23.0));
+        harness.read_temperatures(1).await;
+        assert_eq!(latest(&harness), Some(20.0));
+
+        let rejected = rejected(&harness);
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].0, 45.0);
+        assert!(
+            rejected[0].1.starts_with("out of range"),
+            "{}",
+            rejected[0].1
+        );
+        assert_eq!(rejected[1].0, 23.0);
+        assert!(rejected[1].1.starts_with("changed by"), "{}", rejected[1].1);
+        assert!(harness
+            .metrics
+            .render()
+            .contains("neuroheat_rejected_temperatures_total{key=\"office\"} 2\n"));
+
+        // The controller keeps heating to the last accepted reading.
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+    }
+}
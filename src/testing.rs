@@ -0,0 +1,191 @@
+//! Helpers shared by the unit tests: a configuration with virtual devices,
+//! a manual clock and an in-memory database.
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::ManualClock;
+use crate::controller;
+use crate::db;
+use crate::devices::VirtualDevices;
+use crate::error::NeuroheatError;
+use crate::events::Events;
+use crate::heating_configuration::HeatingConfiguration;
+use crate::metrics::Metrics;
+use crate::relay::VirtualRelayController;
+use crate::repo;
+use crate::temperature_sensor::{self, VirtualTemperatureSensor};
+
+/// Two rooms heated to 21 °C all day. The office alone is large enough to
+/// turn the stove on.
+pub const CONFIG: &str = r#"{
+  "rooms": [
+    {
+      "key": "office",
+      "name": "Office",
+      "sensor_id": "office-sensor",
+      "valve_pin": 1,
+      "area": 20.0,
+      "temperature_schedule": [{ "start_hour": 0, "end_hour": 24, "temperature": 21.0 }]
+    },
+    {
+      "key": "kitchen",
+      "name": "Kitchen",
+      "sensor_id": "kitchen-sensor",
+      "valve_pin": 2,
+      "area": 10.0,
+      "temperature_schedule": [{ "start_hour": 0, "end_hour": 24, "temperature": 21.0 }]
+    }
+  ],
+  "stove_pin": 0,
+  "pipe_sensor_id": "pipe-sensor",
+  "controller": {
+    "hysteresis": 0.2,
+    "min_valve_on_minutes": 10,
+    "min_valve_off_minutes": 10
+  }
+}"#;
+
+/// Parses a test configuration.
+pub fn parse_config(json: &str) -> Result<HeatingConfiguration, NeuroheatError> {
+    HeatingConfiguration::from_reader(json.as_bytes(), "test")
+}
+
+/// Returns the test configuration without devices.
+pub fn config() -> HeatingConfiguration {
+    parse_config(CONFIG).unwrap()
+}
+
+/// Returns the test configuration with the given fields added to the office
+/// (e.g., `"valve_strategy": { "type": "pi" }`).
+pub fn config_with(room_json: &str) -> HeatingConfiguration {
+    parse_config(&config_json_with(room_json)).unwrap()
+}
+
+/// Returns the JSON of the test configuration with the given fields added
+/// to the office.
+pub fn config_json_with(room_json: &str) -> String {
+    CONFIG.replacen(
+        r#""area": 20.0,"#,
+        &format!(r#""area": 20.0, {},"#, room_json),
+        1,
+    )
+}
+
+/// Returns the JSON of the test configuration with the given temperature
+/// schedule for the office.
+pub fn config_json_with_schedule(schedule: &str) -> String {
+    CONFIG.replacen(
+        r#""temperature_schedule": [{ "start_hour": 0, "end_hour": 24, "temperature": 21.0 }]"#,
+        &format!(r#""temperature_schedule": {}"#, schedule),
+        1,
+    )
+}
+
+/// Adds top-level settings (e.g., `"outdoor_sensor_id": "outdoor-sensor"`)
+/// to the JSON of a configuration.
+pub fn with_settings(json: &str, settings: &str) -> String {
+    json.replacen('{', &format!("{{\n  {},", settings), 1)
+}
+
+pub const STOVE_PIN: u8 = 0;
+pub const OFFICE_PIN: u8 = 1;
+pub const KITCHEN_PIN: u8 = 2;
+
+/// Everything the scheduler jobs need, wired to virtual devices.
+pub struct Harness {
+    pub config: Arc<HeatingConfiguration>,
+    pub conn: Arc<Mutex<Connection>>,
+    pub metrics: Arc<Metrics>,
+    pub events: Arc<Events>,
+    pub devices: VirtualDevices,
+    pub clock: Arc<ManualClock>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self::with_config(CONFIG)
+    }
+
+    pub fn with_config(json: &str) -> Self {
+        let devices = VirtualDevices::new();
+        let clock = Arc::new(ManualClock::new(start()));
+
+        let mut config = parse_config(json).unwrap();
+        config.attach_devices(&devices);
+        config.clock = clock.clone();
+
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        db::init(&conn, &config).unwrap();
+
+        Harness {
+            config: Arc::new(config),
+            conn,
+            metrics: Arc::new(Metrics::new(clock.clone())),
+            events: Arc::new(Events::new()),
+            devices,
+            clock,
+        }
+    }
+
+    pub fn advance(&self, minutes: i64) {
+        self.clock.advance(Duration::minutes(minutes));
+    }
+
+    pub fn sensor(&self, sensor_id: &str) -> Arc<VirtualTemperatureSensor> {
+        self.devices.sensor(sensor_id)
+    }
+
+    pub fn relay(&self, pin: u8) -> Arc<VirtualRelayController> {
+        self.devices.relay_at(pin)
+    }
+
+    /// Reads all sensors once a minute for the given number of minutes.
+    pub async fn read_temperatures(&self, minutes: usize) {
+        for _ in 0..minutes {
+            self.advance(1);
+            temperature_sensor::read_temperatures(
+                self.config.clone(),
+                self.conn.clone(),
+                self.metrics.clone(),
+                self.events.clone(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    pub async fn update_valves(&self) -> Result<(), NeuroheatError> {
+        controller::update_valves(
+            self.config.clone(),
+            self.conn.clone(),
+            self.metrics.clone(),
+            self.events.clone(),
+        )
+        .await
+    }
+
+    pub async fn update_stove_state(&self) -> Result<(), NeuroheatError> {
+        controller::update_stove_state(
+            self.config.clone(),
+            self.conn.clone(),
+            self.metrics.clone(),
+            self.events.clone(),
+        )
+        .await
+    }
+
+    /// The latest recorded state of a relay.
+    pub fn stored_state(&self, key: &str) -> Option<bool> {
+        repo::get_valve_states_and_timestamps(&self.conn)
+            .unwrap()
+            .get(key)
+            .map(|(state, _)| *state)
+    }
+}
+
+/// A fixed point in time the clock of a harness starts at.
+pub fn start() -> DateTime<Utc> {
+    "2024-01-15T12:00:00Z".parse().unwrap()
+}
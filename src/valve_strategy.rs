@@ -0,0 +1,333 @@
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use crate::error::NeuroheatError;
+use crate::heating_configuration::{HeatingConfiguration, PiConfiguration, Room};
+use crate::repo;
+
+/// What a strategy knows when deciding on the valve of a room.
+pub struct ValveInput<'a> {
+    pub config: &'a HeatingConfiguration,
+    pub room: &'a Room,
+    pub conn: &'a Arc<Mutex<Connection>>,
+    /// The average temperature within the lookback window.
+    pub average_temperature: f32,
+    pub expected_temperature: f32,
+    /// Whether the valve is open.
+    pub valve_on: bool,
+    pub now: DateTime<Utc>,
+}
+
+/// The desired valve state with the reason for it (used in logs).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValveDecision {
+    pub open: bool,
+    pub reason: String,
+}
+
+/// Decides whether the valve of a room should be open. The minimum on/off
+/// times of the valve are enforced by the controller on top of it.
+pub trait ValveStrategy: Debug + Send + Sync {
+    fn decide(&self, input: &ValveInput) -> Result<ValveDecision, NeuroheatError>;
+}
+
+/// Opens the valve below the hysteresis band around the expected
+/// temperature and closes it above the band.
+#[derive(Debug)]
+pub struct ThresholdStrategy;
+
+impl ValveStrategy for ThresholdStrategy {
+    fn decide(&self, input: &ValveInput) -> Result<ValveDecision, NeuroheatError> {
+        // Keep the current state while the temperature is within the hysteresis band.
+        let hysteresis = input.config.controller.hysteresis(input.room);
+        let (open, comparison) =
+            if input.average_temperature < input.expected_temperature - hysteresis {
+                (true, "less than")
+            } else if input.average_temperature >= input.expected_temperature + hysteresis {
+                (false, "greater than or equal to")
+            } else {
+                (input.valve_on, "within the hysteresis band of")
+            };
+
+        Ok(ValveDecision {
+            open,
+            reason: format!(
+                "Average Temperature: {:.1}°C is {} Expected Temperature: {:.1}°C (±{:.1}°C)",
+                input.average_temperature, comparison, input.expected_temperature, hysteresis
+            ),
+        })
+    }
+}
+
+/// The state of a PI controller kept in the database, so the integral
+/// survives restarts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PiState {
+    /// The accumulated error in °C·h.
+    pub integral: f32,
+    /// The part of the cycle the valve is open (0.0-1.0).
+    pub duty_cycle: f32,
+    /// The start of the cycle the duty cycle was set for.
+    pub cycle_start: DateTime<Utc>,
+}
+
+/// Time-proportional valve control: the valve opens at the start of every
+/// cycle and closes after the duty cycle set by a PI controller. Floor
+/// heating reacts with hours of lag, so the integral term finds the duty
+/// cycle that keeps the room at the expected temperature instead of
+/// overshooting it.
+#[derive(Debug)]
+pub struct PiStrategy {
+    config: PiConfiguration,
+}
+
+impl PiStrategy {
+    pub fn new(config: PiConfiguration) -> Self {
+        PiStrategy { config }
+    }
+
+    fn cycle_length(&self) -> Duration {
+        Duration::minutes(self.config.cycle_minutes as i64)
+    }
+
+    /// Returns the start of the cycle containing `now`. Cycles are aligned
+    /// to the Unix epoch, so a restart does not shift them.
+    fn cycle_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let length = self.cycle_length().num_seconds();
+        let start = now.timestamp().div_euclid(length) * length;
+        DateTime::from_timestamp(start, 0).unwrap_or(now)
+    }
+
+    /// Accumulates the error over a cycle and returns the state of the
+    /// cycle starting at `cycle_start`. Valve pulses shorter than the
+    /// minimum on (or off) time are dropped (or extended to the full cycle).
+    fn next_state(
+        &self,
+        previous: Option<PiState>,
+        error: f32,
+        cycle_start: DateTime<Utc>,
+        min_on: Duration,
+        min_off: Duration,
+    ) -> PiState {
+        let cycle_hours = self.config.cycle_minutes as f32 / 60.0;
+        // The integral alone never asks for more than a fully open (or
+        // closed) valve. It may turn negative, so an overshoot winds it
+        // down; only the duty cycle is limited to 0-100%.
+        let max_integral = if self.config.integral_gain > 0.0 {
+            1.0 / self.config.integral_gain
+        } else {
+            0.0
+        };
+        let integral = (previous.map_or(0.0, |state| state.integral) + error * cycle_hours)
+            .clamp(-max_integral, max_integral);
+
+        let mut duty_cycle = (self.config.proportional_gain * error
+            + self.config.integral_gain * integral)
+            .clamp(0.0, 1.0);
+        let cycle_seconds = self.cycle_length().num_seconds() as f32;
+        if duty_cycle * cycle_seconds < min_on.num_seconds() as f32 {
+            duty_cycle = 0.0;
+        } else if (1.0 - duty_cycle) * cycle_seconds < min_off.num_seconds() as f32 {
+            duty_cycle = 1.0;
+        }
+
+        PiState {
+            integral,
+            duty_cycle,
+            cycle_start,
+        }
+    }
+}
+
+impl ValveStrategy for PiStrategy {
+    fn decide(&self, input: &ValveInput) -> Result<ValveDecision, NeuroheatError> {
+        let room = input.room;
+        let cycle_start = self.cycle_start(input.now);
+
+        let state = match repo::get_pi_state(input.conn, &room.key)? {
+            Some(state) if state.cycle_start == cycle_start => state,
+            previous => {
+                let state = self.next_state(
+                    previous,
+                    input.expected_temperature - input.average_temperature,
+                    cycle_start,
+                    input.config.controller.min_valve_duration(room, true),
+                    input.config.controller.min_valve_duration(room, false),
+                );
+                repo::store_pi_state(input.conn, &room.key, &state)?;
+                log::info!(
+                    "Room: {}, Duty cycle: {:.0}% (integral {:.2}°C·h) for the cycle starting at {}",
+                    room.name,
+                    state.duty_cycle * 100.0,
+                    state.integral,
+                    cycle_start
+                );
+                state
+            }
+        };
+
+        let elapsed = input.now - cycle_start;
+        let on_seconds = self.cycle_length().num_seconds() as f32 * state.duty_cycle;
+
+        Ok(ValveDecision {
+            open: (elapsed.num_seconds() as f32) < on_seconds,
+            reason: format!(
+                "Average Temperature: {:.1}°C, Expected Temperature: {:.1}°C, duty cycle {:.0}% ({} of {} min elapsed)",
+                input.average_temperature,
+                input.expected_temperature,
+                state.duty_cycle * 100.0,
+                elapsed.num_minutes(),
+                self.config.cycle_minutes
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayController;
+    use crate::testing::{config_json_with, start, Harness, OFFICE_PIN};
+
+    fn pi_harness() -> Harness {
+        let harness = Harness::with_config(&config_json_with(
+            r#""valve_strategy": { "type": "pi", "cycle_minutes": 30 }"#,
+        ));
+        harness.sensor("kitchen-sensor").set(Some(22.0));
+        harness.sensor("pipe-sensor").set(Some(30.0));
+        harness
+    }
+
+    #[test]
+    fn drops_pulses_shorter_than_the_minimum_times() {
+        let strategy = PiStrategy::new(PiConfiguration::default());
+        let next = |error| {
+            strategy
+                .next_state(
+                    None,
+                    error,
+                    start(),
+                    Duration::minutes(10),
+                    Duration::minutes(10),
+                )
+                .duty_cycle
+        };
+
+        assert!((next(1.0) - 0.55).abs() < 1e-6);
+        // 8% of 30 minutes is shorter than the minimum on-time
+        assert_eq!(next(0.15), 0.0);
+        // 26 minutes on leaves less than the minimum off-time
+        assert_eq!(next(1.6), 1.0);
+        assert_eq!(next(-1.0), 0.0);
+    }
+
+    #[test]
+    fn limits_the_integral_in_both_directions() {
+        let strategy = PiStrategy::new(PiConfiguration::default());
+        let next = |integral, error| {
+            let previous = PiState {
+                integral,
+                duty_cycle: 0.0,
+                cycle_start: start() - Duration::minutes(30),
+            };
+            strategy.next_state(
+                Some(previous),
+                error,
+                start(),
+                Duration::zero(),
+                Duration::zero(),
+            )
+        };
+
+        let overshoot = next(0.2, -1.0);
+        assert!((overshoot.integral + 0.3).abs() < 1e-6);
+        assert_eq!(overshoot.duty_cycle, 0.0);
+        assert_eq!(next(-9.9, -1.0).integral, -10.0);
+        assert_eq!(next(9.9, 1.0).integral, 10.0);
+        // A negative integral keeps the valve closed for a small error.
+        assert_eq!(next(-5.0, 0.5).duty_cycle, 0.0);
+    }
+
+    #[tokio::test]
+    async fn opens_the_valve_for_the_duty_cycle() {
+        let harness = pi_harness();
+        harness.sensor("office-sensor").set(Some(20.0));
+
+        harness.read_temperatures(5).await;
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+        let state = repo::get_pi_state(&harness.conn, "office")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.cycle_start, start());
+        assert!((state.integral - 0.5).abs() < 1e-6);
+        assert!((state.duty_cycle - 0.55).abs() < 1e-6);
+
+        // 17 of 30 minutes are past the 55% duty cycle
+        harness.read_temperatures(12).await;
+        harness.update_valves().await.unwrap();
+        assert!(!harness.relay(OFFICE_PIN).read_state().unwrap());
+
+        // The room is still cold at the next cycle, so the integral grows.
+        harness.read_temperatures(13).await;
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+        let state = repo::get_pi_state(&harness.conn, "office")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.cycle_start, start() + Duration::minutes(30));
+        assert!((state.duty_cycle - 0.6).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn continues_from_the_stored_integral() {
+        let harness = pi_harness();
+        harness.sensor("office-sensor").set(Some(21.0));
+        let stored = PiState {
+            integral: 5.0,
+            duty_cycle: 0.0,
+            cycle_start: start() - Duration::minutes(30),
+        };
+        repo::store_pi_state(&harness.conn, "office", &stored).unwrap();
+
+        // Without an error, the accumulated one keeps the valve open for
+        // half of the cycle.
+        harness.read_temperatures(5).await;
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+        let state = repo::get_pi_state(&harness.conn, "office")
+            .unwrap()
+            .unwrap();
+        assert!((state.duty_cycle - 0.5).abs() < 1e-6);
+    }
+    #[tokio::test]
+    async fn closes_the_valve_once_the_room_overshoots() {
+        let harness = pi_harness();
+        let office = harness.sensor("office-sensor");
+        office.set(Some(20.0));
+        let stored = PiState {
+            integral: 5.0,
+            duty_cycle: 0.5,
+            cycle_start: start() - Duration::minutes(30),
+        };
+        repo::store_pi_state(&harness.conn, "office", &stored).unwrap();
+
+        harness.read_temperatures(5).await;
+        harness.update_valves().await.unwrap();
+        assert!(harness.relay(OFFICE_PIN).read_state().unwrap());
+
+        // Warmer than expected by the next cycle.
+        office.set(Some(22.5));
+        harness.read_temperatures(25).await;
+        harness.update_valves().await.unwrap();
+        assert!(!harness.relay(OFFICE_PIN).read_state().unwrap());
+        let state = repo::get_pi_state(&harness.conn, "office")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.cycle_start, start() + Duration::minutes(30));
+        assert_eq!(state.duty_cycle, 0.0);
+        assert!((state.integral - 4.75).abs() < 1e-6);
+    }
+}